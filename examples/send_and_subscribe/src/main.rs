@@ -6,6 +6,7 @@ use stomp::subscription::AckOrNack::Ack;
 use stomp::subscription::AckMode;
 use stomp::connection::{HeartBeat, Credentials};
 use stomp::session::ReceiptHandler;
+use stomp::broker_error::BrokerError;
 
 fn main() {
   env_logger::init().unwrap();
@@ -23,8 +24,8 @@ fn main() {
       Err(error)  => panic!("Could not connect to the server: {}", error)
    };
 
-  session.on_error(|frame: &Frame| {
-    println!("Something went horribly wrong: {}", frame);
+  session.on_error(|broker_error: &BrokerError| {
+    println!("Something went horribly wrong: {}", broker_error);
   });
 
   let _ = session.subscription(destination, |frame: &Frame| {
@@ -44,13 +44,16 @@ fn main() {
         Err(error)  => panic!("Could not connect to the server: {}", error)
       };
     loop {
-      let _ = publish_session.message(destination, "Modern major general")
-      .with(ContentType("text/plain"))
-      .send();
-      messages_sent += 1;
-      if messages_sent % INTERVAL == 0 {
-        println!("{} messages sent", messages_sent);
+      // Encode a batch of messages and write them to the socket in one go
+      let mut batch = publish_session.batch();
+      for _ in 0..INTERVAL {
+        let _ = batch.message(destination, "Modern major general")
+        .with(ContentType("text/plain"))
+        .send();
       }
+      let _ = batch.send();
+      messages_sent += INTERVAL;
+      println!("{} messages sent", messages_sent);
       if messages_sent >= TOTAL_MESSAGES {
         println!("Send complete.");
         break;
@@ -0,0 +1,36 @@
+use frame::ToFrameBody;
+use message_builder::MessageBuilder;
+use session::Session;
+use std::io::Result;
+
+pub struct Batch<'a, 'session: 'a> {
+  pub session: &'a mut Session<'session>
+}
+
+impl <'a, 'session> Batch<'a, 'session> {
+  pub fn new<'b>(session: &'b mut Session<'session>) -> Batch<'b, 'session> {
+    session.start_batch();
+    Batch {
+      session: session
+    }
+  }
+
+  pub fn message<'b, T: ToFrameBody> (&'b mut self, destination: &str, body_convertible: T) -> MessageBuilder<'b, 'session> {
+    self.session.message(destination, body_convertible)
+  }
+
+  pub fn len(&self) -> usize {
+    self.session.batched_frames()
+  }
+
+  pub fn send(self) -> Result<()> {
+    self.session.flush_batch()
+  }
+}
+
+impl <'a, 'session> Drop for Batch<'a, 'session> {
+  fn drop(&mut self) {
+    // Does nothing if the batch was already sent
+    self.session.discard_batch();
+  }
+}
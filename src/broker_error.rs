@@ -0,0 +1,94 @@
+use std::error;
+use std::fmt;
+use std::str::from_utf8;
+use frame::Frame;
+use header::StompHeaderSet;
+
+// The contents of an ERROR frame. The broker closes the connection after sending one.
+#[derive(Clone, Debug)]
+pub struct BrokerError {
+  // A short description from the 'message' header
+  pub message: Option<String>,
+  // Set if the error was caused by a frame that requested a receipt
+  pub receipt_id: Option<String>,
+  // Set if that frame was journaled in the outbox. It is removed rather than resent, since
+  // the broker would refuse it again.
+  pub removed_from_outbox: bool,
+  pub content_type: Option<String>,
+  // Often a more detailed, human readable description
+  pub body: Vec<u8>
+}
+
+// What the session does once the broker has sent an ERROR frame
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BrokerErrorPolicy {
+  Reconnect,
+  // listen() returns an error of kind ConnectionAborted that wraps the BrokerError
+  EndListen
+}
+
+impl BrokerError {
+  pub fn from_frame(frame: &Frame) -> BrokerError {
+    let header_value = |key: &str| frame.headers.get_header(key).map(|header| header.get_value().to_string());
+    BrokerError {
+      message: header_value("message"),
+      receipt_id: header_value("receipt-id"),
+      removed_from_outbox: false,
+      content_type: header_value("content-type"),
+      body: frame.body.clone()
+    }
+  }
+
+  // The body, if it is text
+  pub fn body_text(&self) -> Option<&str> {
+    from_utf8(&self.body).ok().filter(|text| !text.is_empty())
+  }
+
+  // Which io::Error, if any, carries a BrokerError
+  pub fn of(error: &::std::io::Error) -> Option<&BrokerError> {
+    error.get_ref().and_then(|inner| inner.downcast_ref::<BrokerError>())
+  }
+}
+
+impl fmt::Display for BrokerError {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    try!(write!(formatter, "Broker sent an ERROR frame"));
+    if let Some(ref message) = self.message {
+      try!(write!(formatter, ": {}", message));
+    }
+    if let Some(ref receipt_id) = self.receipt_id {
+      try!(write!(formatter, " (receipt '{}')", receipt_id));
+    }
+    if let Some(body) = self.body_text() {
+      try!(write!(formatter, "\n{}", body.trim_end()));
+    }
+    Ok(())
+  }
+}
+
+impl error::Error for BrokerError {}
+
+#[test]
+fn error_frames_are_parsed() {
+  use header::{Header, HeaderList};
+  let mut headers = HeaderList::new();
+  headers.push(Header::new("message", "malformed frame received"));
+  headers.push(Header::new("receipt-id", "message-12345"));
+  headers.push(Header::new("content-type", "text/plain"));
+  let frame = Frame {
+    command: "ERROR".to_string(),
+    headers: headers,
+    body: "The message had no destination.\n".as_bytes().to_vec()
+  };
+  let broker_error = BrokerError::from_frame(&frame);
+  assert_eq!(Some("malformed frame received".to_string()), broker_error.message);
+  assert_eq!(Some("message-12345".to_string()), broker_error.receipt_id);
+  assert_eq!(Some("text/plain".to_string()), broker_error.content_type);
+  assert_eq!(
+    "Broker sent an ERROR frame: malformed frame received (receipt 'message-12345')\nThe message had no destination.",
+    broker_error.to_string()
+  );
+
+  let error = ::std::io::Error::new(::std::io::ErrorKind::ConnectionAborted, broker_error);
+  assert_eq!(Some("message-12345"), BrokerError::of(&error).and_then(|broker_error| broker_error.receipt_id.as_ref().map(|id| id.as_ref())));
+}
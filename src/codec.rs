@@ -0,0 +1,143 @@
+use std::io::Result;
+use std::io::Error;
+use std::io::ErrorKind::InvalidData;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+use frame::Frame;
+use header::{HeaderList, StompHeaderSet};
+use subscription::{AckOrNack, MessageHandler};
+
+pub trait Codec {
+  fn content_type(&self) -> &str;
+  fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>>;
+  fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T>;
+}
+
+#[derive(Clone, Copy)]
+pub struct Json;
+
+impl Codec for Json {
+  fn content_type(&self) -> &str {
+    "application/json"
+  }
+
+  fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+    serde_json::to_vec(value).map_err(|error| Error::new(InvalidData, error))
+  }
+
+  fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T> {
+    serde_json::from_slice(body).map_err(|error| Error::new(InvalidData, error))
+  }
+}
+
+// A decoded message body along with the headers of the frame that carried it
+pub struct Typed<T> {
+  pub body: T,
+  pub headers: HeaderList
+}
+
+impl <T> Deref for Typed<T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    &self.body
+  }
+}
+
+pub struct TypedHandler<'a, T, C, F> {
+  codec: C,
+  handler: F,
+  decode_error_callback: Box<FnMut(&Frame, &Error) -> AckOrNack + 'a>,
+  body_type: PhantomData<fn() -> T>
+}
+
+pub fn typed<'a, T, F>(handler: F) -> TypedHandler<'a, T, Json, F> where T: DeserializeOwned, F: FnMut(Typed<T>) -> AckOrNack {
+  typed_with(Json, handler)
+}
+
+pub fn typed_with<'a, T, C, F>(codec: C, handler: F) -> TypedHandler<'a, T, C, F> where T: DeserializeOwned, C: Codec, F: FnMut(Typed<T>) -> AckOrNack {
+  TypedHandler {
+    codec: codec,
+    handler: handler,
+    decode_error_callback: Box::new(nack_undecodable_message),
+    body_type: PhantomData
+  }
+}
+
+fn nack_undecodable_message(frame: &Frame, error: &Error) -> AckOrNack {
+  match frame.headers.get_header("message-id") {
+    Some(message_id) => warn!("Could not decode message '{}': {}", message_id.get_value(), error),
+    None => warn!("Could not decode message: {}", error)
+  }
+  AckOrNack::Nack
+}
+
+impl <'a, T, C, F> TypedHandler<'a, T, C, F> {
+  // Replaces the default behaviour of NACKing messages that cannot be decoded
+  pub fn on_decode_error<E>(mut self, callback: E) -> TypedHandler<'a, T, C, F> where E: FnMut(&Frame, &Error) -> AckOrNack + 'a {
+    self.decode_error_callback = Box::new(callback);
+    self
+  }
+}
+
+impl <'a, T, C, F> MessageHandler for TypedHandler<'a, T, C, F> where T: DeserializeOwned, C: Codec, F: FnMut(Typed<T>) -> AckOrNack {
+  fn on_message(&mut self, frame: &Frame) -> AckOrNack {
+    match self.codec.decode(&frame.body) {
+      Ok(body) => (self.handler)(Typed {
+        body: body,
+        headers: frame.headers.clone()
+      }),
+      Err(error) => (self.decode_error_callback)(frame, &error)
+    }
+  }
+}
+
+#[cfg(test)]
+use std::collections::BTreeMap;
+
+#[cfg(test)]
+fn message_with_body(body: &str) -> Frame {
+  Frame {
+    command: "MESSAGE".to_string(),
+    headers: HeaderList::new(),
+    body: body.as_bytes().to_vec()
+  }
+}
+
+#[test]
+fn json_round_trips() {
+  let mut inventory = BTreeMap::new();
+  inventory.insert("giraffes".to_string(), 2u32);
+  let body = Json.encode(&inventory).unwrap();
+  assert_eq!("{\"giraffes\":2}".as_bytes(), &body[..]);
+  let decoded: BTreeMap<String, u32> = Json.decode(&body).unwrap();
+  assert_eq!(inventory, decoded);
+}
+
+#[test]
+fn undecodable_messages_go_to_the_error_callback() {
+  let mut decoded_counts = Vec::new();
+  let mut decode_errors = 0;
+  {
+    let mut handler = typed(|inventory: Typed<BTreeMap<String, u32>>| {
+      decoded_counts.push(inventory["giraffes"]);
+      AckOrNack::Ack
+    }).on_decode_error(|_: &Frame, _: &Error| {
+      decode_errors += 1;
+      AckOrNack::Nack
+    });
+    match handler.on_message(&message_with_body("{\"giraffes\":2}")) {
+      AckOrNack::Ack => {},
+      AckOrNack::Nack => panic!("Expected the message to be acknowledged.")
+    }
+    match handler.on_message(&message_with_body("giraffes: 2")) {
+      AckOrNack::Nack => {},
+      AckOrNack::Ack => panic!("Expected the message to be rejected.")
+    }
+  }
+  assert_eq!(vec![2], decoded_counts);
+  assert_eq!(1, decode_errors);
+}
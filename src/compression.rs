@@ -0,0 +1,219 @@
+use std::io::Result;
+use std::io::Error;
+use std::io::ErrorKind::InvalidInput;
+#[cfg(any(feature = "gzip", feature = "deflate", feature = "zstd"))]
+use std::io::ErrorKind::InvalidData;
+#[cfg(any(feature = "gzip", feature = "deflate", feature = "zstd"))]
+use std::io::Read;
+#[cfg(any(feature = "gzip", feature = "deflate"))]
+use std::io::Write;
+use frame::Frame;
+use header::{Header, StompHeaderSet};
+
+const DEFAULT_THRESHOLD: usize = 1024;
+// Guards against bodies that expand enormously when decoded
+pub const DEFAULT_MAX_DECODED_SIZE: usize = 64 * 1024 * 1024;
+
+// Each encoding is only available when its cargo feature is enabled
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ContentEncoding {
+  #[cfg(feature = "gzip")]
+  Gzip,
+  #[cfg(feature = "deflate")]
+  Deflate,
+  #[cfg(feature = "zstd")]
+  Zstd
+}
+
+impl ContentEncoding {
+  pub fn from_header_value(value: &str) -> Option<ContentEncoding> {
+    match value.trim() {
+      #[cfg(feature = "gzip")]
+      "gzip" => Some(ContentEncoding::Gzip),
+      #[cfg(feature = "deflate")]
+      "deflate" => Some(ContentEncoding::Deflate),
+      #[cfg(feature = "zstd")]
+      "zstd" => Some(ContentEncoding::Zstd),
+      _ => None
+    }
+  }
+
+  pub fn header_value(&self) -> &'static str {
+    match *self {
+      #[cfg(feature = "gzip")]
+      ContentEncoding::Gzip => "gzip",
+      #[cfg(feature = "deflate")]
+      ContentEncoding::Deflate => "deflate",
+      #[cfg(feature = "zstd")]
+      ContentEncoding::Zstd => "zstd"
+    }
+  }
+
+  #[cfg_attr(not(any(feature = "gzip", feature = "deflate", feature = "zstd")), allow(unused_variables))]
+  pub fn compress(&self, body: &[u8]) -> Result<Vec<u8>> {
+    match *self {
+      #[cfg(feature = "gzip")]
+      ContentEncoding::Gzip => {
+        let mut encoder = ::flate2::write::GzEncoder::new(Vec::new(), ::flate2::Compression::default());
+        try!(encoder.write_all(body));
+        encoder.finish()
+      },
+      #[cfg(feature = "deflate")]
+      ContentEncoding::Deflate => {
+        let mut encoder = ::flate2::write::DeflateEncoder::new(Vec::new(), ::flate2::Compression::default());
+        try!(encoder.write_all(body));
+        encoder.finish()
+      },
+      #[cfg(feature = "zstd")]
+      ContentEncoding::Zstd => ::zstd::encode_all(body, 0)
+    }
+  }
+
+  // Fails once the decoded body would grow beyond `max_size` bytes
+  #[cfg_attr(not(any(feature = "gzip", feature = "deflate", feature = "zstd")), allow(unused_variables))]
+  pub fn decompress(&self, body: &[u8], max_size: usize) -> Result<Vec<u8>> {
+    match *self {
+      #[cfg(feature = "gzip")]
+      ContentEncoding::Gzip => read_at_most(::flate2::read::GzDecoder::new(body), max_size),
+      #[cfg(feature = "deflate")]
+      ContentEncoding::Deflate => read_at_most(::flate2::read::DeflateDecoder::new(body), max_size),
+      #[cfg(feature = "zstd")]
+      ContentEncoding::Zstd => read_at_most(try!(::zstd::stream::read::Decoder::new(body)), max_size)
+    }
+  }
+}
+
+#[cfg(any(feature = "gzip", feature = "deflate", feature = "zstd"))]
+fn read_at_most<R: Read>(decoder: R, max_size: usize) -> Result<Vec<u8>> {
+  let mut decompressed = Vec::new();
+  try!(decoder.take(max_size as u64 + 1).read_to_end(&mut decompressed));
+  if decompressed.len() > max_size {
+    return Err(Error::new(InvalidData, format!("Decoded body would be larger than {} bytes.", max_size)));
+  }
+  Ok(decompressed)
+}
+
+#[derive(Clone, Copy)]
+pub struct Compression {
+  pub encoding: ContentEncoding,
+  pub threshold: usize,
+  pub max_decoded_size: usize
+}
+
+impl Compression {
+  pub fn new(encoding: ContentEncoding) -> Compression {
+    Compression {
+      encoding: encoding,
+      threshold: DEFAULT_THRESHOLD,
+      max_decoded_size: DEFAULT_MAX_DECODED_SIZE
+    }
+  }
+
+  // Bodies smaller than this are sent as they are
+  pub fn over(mut self, bytes: usize) -> Compression {
+    self.threshold = bytes;
+    self
+  }
+
+  // Incoming bodies that decode to more than this are delivered still encoded
+  pub fn max_decoded(mut self, bytes: usize) -> Compression {
+    self.max_decoded_size = bytes;
+    self
+  }
+
+  // Returns whether the frame's body was replaced with a compressed one
+  pub fn compress_frame(&self, frame: &mut Frame) -> Result<bool> {
+    if frame.command != "SEND" || frame.body.len() < self.threshold {
+      return Ok(false);
+    }
+    if frame.headers.get_header("content-encoding").is_some() {
+      // The application has already encoded this body itself
+      return Ok(false);
+    }
+    let compressed_body = try!(self.encoding.compress(&frame.body));
+    if compressed_body.len() >= frame.body.len() {
+      debug!("Compression would not make a {} byte body smaller, sending it as is.", frame.body.len());
+      return Ok(false);
+    }
+    debug!("Compressed a {} byte body to {} bytes using '{}'.", frame.body.len(), compressed_body.len(), self.encoding.header_value());
+    frame.body = compressed_body;
+    frame.headers.push(Header::new("content-encoding", self.encoding.header_value()));
+    set_content_length(frame);
+    Ok(true)
+  }
+}
+
+// Replaces a compressed body with the original one and drops the 'content-encoding' header.
+// Frames using an encoding that was not compiled in are left untouched.
+pub fn decompress_frame(frame: &mut Frame, max_size: usize) -> Result<bool> {
+  let encoding = match frame.headers.get_header("content-encoding") {
+    Some(header) => match ContentEncoding::from_header_value(header.get_value()) {
+      Some(encoding) => encoding,
+      None => {
+        debug!("Leaving body with unsupported content-encoding '{}' as is.", header.get_value());
+        return Ok(false);
+      }
+    },
+    None => return Ok(false)
+  };
+  let decompressed_body = try!(encoding.decompress(&frame.body, max_size)
+    .map_err(|error| Error::new(InvalidInput, format!("Could not decode '{}' body: {}", encoding.header_value(), error))));
+  frame.body = decompressed_body;
+  frame.headers.retain(|header| header.get_key() != "content-encoding");
+  set_content_length(frame);
+  Ok(true)
+}
+
+fn set_content_length(frame: &mut Frame) {
+  frame.headers.retain(|header| header.get_key() != "content-length");
+  frame.headers.push(Header::new("content-length", frame.body.len().to_string().as_ref()));
+}
+
+#[cfg(all(test, feature = "gzip"))]
+fn large_message() -> Frame {
+  let body = "{\"animal\": \"giraffe\", \"vegetable\": \"carrot\", \"mineral\": \"quartz\"}".repeat(64);
+  Frame::send("/queue/catalog", body.as_bytes())
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn compressed_bodies_round_trip() {
+  let mut frame = large_message();
+  let original_body = frame.body.clone();
+  assert!(Compression::new(ContentEncoding::Gzip).compress_frame(&mut frame).unwrap());
+  assert!(frame.body.len() < original_body.len());
+  assert_eq!("gzip", frame.headers.get_header("content-encoding").unwrap().get_value());
+  assert_eq!(frame.body.len() as u32, frame.headers.get_content_length().unwrap().0);
+
+  // The broker passes the encoding headers on to subscribers
+  let mut message = Frame {
+    command: "MESSAGE".to_string(),
+    headers: frame.headers.clone(),
+    body: frame.body.clone()
+  };
+  assert!(decompress_frame(&mut message, DEFAULT_MAX_DECODED_SIZE).unwrap());
+  assert_eq!(original_body, message.body);
+  assert!(message.headers.get_header("content-encoding").is_none());
+  assert_eq!(original_body.len() as u32, message.headers.get_content_length().unwrap().0);
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn small_bodies_are_not_compressed() {
+  let mut frame = Frame::send("/queue/catalog", "Animal".as_bytes());
+  assert!(!Compression::new(ContentEncoding::Gzip).compress_frame(&mut frame).unwrap());
+  assert_eq!("Animal".as_bytes(), &frame.body[..]);
+  assert!(frame.headers.get_header("content-encoding").is_none());
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn decoded_bodies_are_limited_in_size() {
+  let mut frame = large_message();
+  let original_length = frame.body.len();
+  assert!(Compression::new(ContentEncoding::Gzip).compress_frame(&mut frame).unwrap());
+  let compressed_body = frame.body.clone();
+  assert!(decompress_frame(&mut frame.clone(), original_length).unwrap());
+  assert!(decompress_frame(&mut frame, original_length - 1).is_err());
+  assert_eq!(compressed_body, frame.body);
+}
@@ -1,34 +1,247 @@
 //use std::old_io::net::tcp::TcpStream;
-use std::net::TcpStream;
+use std::net::{TcpStream, SocketAddr, ToSocketAddrs};
+use std::error;
+use std::fmt;
+use std::time::Duration;
 use std::io::BufReader;
-use std::io::BufWriter;
+use std::os::unix::net::UnixStream;
 use frame::Transmission;
 use std::io::Result;
 use std::io::Error;
 use std::io::ErrorKind;
 use frame::Frame;
 use std::cmp::max;
-use header::{self, StompHeaderSet};
+use header::{self, StompHeaderSet, StompVersion};
+use transport::{Transport, TcpTransport};
+use proxy::Proxy;
+use broker_error::BrokerError;
+#[cfg(feature = "tls")]
+use tls::Tls;
+#[cfg(feature = "websocket")]
+use websocket::{WebSocket, WebSocketStream};
+
+// Connect to the broker over a Unix domain socket instead of TCP
+#[derive(Clone, Copy)]
+pub struct UnixSocket<'a>(pub &'a str);
 
 pub struct Connection {
+  // For Unix domain sockets this is the socket's path and the port is 0
   pub ip_address : String,
   pub port: u16,
-  pub tcp_stream : TcpStream
+  pub stream : Box<Transport>,
+  // The address the host name resolved to, for TCP connections. When connecting
+  // through a proxy this is the proxy's address.
+  pub peer_address: Option<SocketAddr>,
+  // Transports supplied by the application are reopened on reconnect instead of
+  // being replaced by a new connection from the session builder
+  pub reopen_transport: bool,
+  // Taken from the CONNECTED frame; brokers that don't send a version speak STOMP 1.0
+  pub version: StompVersion,
+  // Also from the CONNECTED frame, if the broker sent them
+  pub server: Option<String>,
+  pub session_id: Option<String>
+}
+
+// How long opening a session may block; None waits indefinitely.
+// The handshake timeout applies to each read while waiting for CONNECTED (and for TLS or
+// WebSocket handshakes), the write timeout to writes made outside of listen().
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Timeouts {
+  pub connect: Option<Duration>,
+  pub handshake: Option<Duration>,
+  pub write: Option<Duration>
+}
+
+#[derive(Clone, Copy)]
+pub struct ConnectTimeout(pub Duration);
+#[derive(Clone, Copy)]
+pub struct HandshakeTimeout(pub Duration);
+#[derive(Clone, Copy)]
+pub struct WriteTimeout(pub Duration);
+
+// Carried inside io::Errors of kind TimedOut to tell the timeouts apart
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TimeoutKind {
+  Connect,
+  Handshake,
+  Write
+}
+
+impl TimeoutKind {
+  pub fn error(self) -> Error {
+    Error::new(ErrorKind::TimedOut, self)
+  }
+
+  // Which timeout, if any, caused the error
+  pub fn of(error: &Error) -> Option<TimeoutKind> {
+    error.get_ref()
+      .and_then(|inner| inner.downcast_ref::<TimeoutKind>())
+      .cloned()
+  }
+}
+
+impl fmt::Display for TimeoutKind {
+  fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    let description = match *self {
+      TimeoutKind::Connect => "Timed out connecting to the server.",
+      TimeoutKind::Handshake => "Timed out waiting for the server to accept the connection.",
+      TimeoutKind::Write => "Timed out writing to the server."
+    };
+    write!(formatter, "{}", description)
+  }
+}
+
+impl error::Error for TimeoutKind {}
+
+// Blocking sockets report an expired timeout as either of these, depending on the platform
+pub fn is_timeout(error: &Error) -> bool {
+  error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut
+}
+
+// Looks up every A and AAAA record for the host. IPv6 literals may be given with or without brackets.
+pub fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+  let addresses: Vec<SocketAddr> = try!((unbracketed(host), port).to_socket_addrs()).collect();
+  if addresses.is_empty() {
+    return Err(Error::new(ErrorKind::InvalidInput, format!("Could not resolve '{}'.", host)));
+  }
+  debug!("Resolved '{}' to {:?}.", host, addresses);
+  Ok(addresses)
+}
+
+pub fn unbracketed(host: &str) -> &str {
+  host.trim_start_matches('[').trim_end_matches(']')
+}
+
+// Resolves the host anew, connects and applies the handshake timeout to the new socket
+pub fn connect_tcp(host: &str, port: u16, timeouts: Timeouts) -> Result<TcpStream> {
+  let addresses = try!(resolve(host, port));
+  let tcp_stream = try!(connect_to_any(&addresses, timeouts.connect));
+  try!(tcp_stream.set_read_timeout(timeouts.handshake));
+  try!(tcp_stream.set_write_timeout(timeouts.handshake));
+  Ok(tcp_stream)
+}
+
+// Connects directly or through the proxy, if there is one
+pub fn open_tcp(host: &str, port: u16, timeouts: Timeouts, proxy: Option<&Proxy>) -> Result<TcpStream> {
+  match proxy {
+    Some(proxy) => proxy.connect(host, port, timeouts),
+    None => connect_tcp(host, port, timeouts)
+  }
+}
+
+// Tries each address in turn, so a host that is down doesn't hide the others.
+// The connect timeout applies to each attempt.
+pub fn connect_to_any(addresses: &[SocketAddr], timeout: Option<Duration>) -> Result<TcpStream> {
+  let mut last_error = Error::new(ErrorKind::InvalidInput, "No addresses to connect to.");
+  for address in addresses {
+    let attempt = match timeout {
+      Some(timeout) => TcpStream::connect_timeout(address, timeout),
+      None => TcpStream::connect(address)
+    };
+    match attempt {
+      Ok(tcp_stream) => {
+        info!("Connected to {}.", address);
+        return Ok(tcp_stream);
+      },
+      Err(error) => {
+        info!("Could not connect to {}: {}", address, error);
+        last_error = match timeout {
+          Some(_) if is_timeout(&error) => TimeoutKind::Connect.error(),
+          _ => error
+        };
+      }
+    }
+  }
+  Err(last_error)
 }
 
 #[derive(Clone, Copy)]
 pub struct HeartBeat(pub u32, pub u32);
+// Which fraction of the negotiated interval we wait before sending a heartbeat, in (0, 1]
+#[derive(Clone, Copy)]
+pub struct HeartBeatSendFraction(pub f64);
+// How many negotiated intervals may pass without traffic from the broker, at least 1
+#[derive(Clone, Copy)]
+pub struct HeartBeatGrace(pub f64);
+
+pub const DEFAULT_HEARTBEAT_SEND_FRACTION: f64 = 0.5;
+pub const DEFAULT_HEARTBEAT_GRACE: f64 = 2.0;
 #[derive(Clone, Copy)]
 pub struct Credentials<'a>(pub &'a str, pub &'a str); 
   
 impl Connection {
 
-  pub fn new(ip_address: &str, port: u16) -> Result<Connection> {
-    let tcp_stream = try!(TcpStream::connect((ip_address, port)));
+  pub fn new(ip_address: &str, port: u16, timeouts: Timeouts, proxy: Option<&Proxy>) -> Result<Connection> {
+    let tcp_transport = try!(TcpTransport::new(try!(open_tcp(ip_address, port, timeouts, proxy))));
+    Ok(Connection {
+      ip_address: ip_address.to_string(),
+      port: port,
+      peer_address: Some(tcp_transport.peer_address()),
+      stream: Box::new(tcp_transport),
+      reopen_transport: false,
+      version: StompVersion::Stomp_v1_2,
+      server: None,
+      session_id: None
+    })
+  }
+
+  pub fn new_unix(path: &str, timeouts: Timeouts) -> Result<Connection> {
+    let unix_stream = try!(UnixStream::connect(path));
+    try!(unix_stream.set_read_timeout(timeouts.handshake));
+    try!(unix_stream.set_write_timeout(timeouts.handshake));
+    Ok(Connection {
+      ip_address: path.to_string(),
+      port: 0,
+      peer_address: None,
+      stream: Box::new(unix_stream),
+      reopen_transport: false,
+      version: StompVersion::Stomp_v1_2,
+      server: None,
+      session_id: None
+    })
+  }
+
+  #[cfg(feature = "tls")]
+  pub fn new_tls(ip_address: &str, port: u16, tls: &Tls, timeouts: Timeouts, proxy: Option<&Proxy>) -> Result<Connection> {
+    let tls_transport = try!(tls.connect(ip_address, port, timeouts, proxy));
     Ok(Connection {
       ip_address: ip_address.to_string(),
       port: port,
-      tcp_stream: tcp_stream
+      peer_address: tls_transport.get_ref().get_ref().peer_addr().ok(),
+      stream: Box::new(tls_transport),
+      reopen_transport: false,
+      version: StompVersion::Stomp_v1_2,
+      server: None,
+      session_id: None
+    })
+  }
+
+  pub fn with_transport(ip_address: &str, port: u16, transport: Box<Transport>) -> Connection {
+    Connection {
+      ip_address: ip_address.to_string(),
+      port: port,
+      peer_address: None,
+      stream: transport,
+      reopen_transport: true,
+      version: StompVersion::Stomp_v1_2,
+      server: None,
+      session_id: None
+    }
+  }
+
+  // Performs the HTTP upgrade; STOMP frames are exchanged inside WebSocket messages afterwards
+  #[cfg(feature = "websocket")]
+  pub fn upgrade_to_websocket(self, websocket: &WebSocket) -> Result<Connection> {
+    let websocket_stream = try!(WebSocketStream::connect(self.stream, &self.ip_address, self.port, websocket));
+    Ok(Connection {
+      ip_address: self.ip_address,
+      port: self.port,
+      peer_address: self.peer_address,
+      stream: Box::new(websocket_stream),
+      reopen_transport: self.reopen_transport,
+      version: self.version,
+      server: self.server,
+      session_id: self.session_id
     })
   }
 
@@ -48,29 +261,138 @@ impl Connection {
     (heartbeat_tx_ms, heartbeat_rx_ms)
   }
 
-  pub fn start_session_with_frame(&mut self, connect_frame: Frame) -> Result<(u32, u32)> {
-    let mut buffered_writer = BufWriter::new(self.tcp_stream.try_clone().unwrap());
-    try!(connect_frame.write(&mut buffered_writer));
+  // Turns the negotiated (tx, rx) intervals into how often we send and how long we wait to receive
+  pub fn heartbeat_timers(negotiated_ms: (u32, u32), send_fraction: f64, grace: f64) -> (u64, u64) {
+    let (tx_ms, rx_ms) = negotiated_ms;
+    let send_ms = match tx_ms {
+      0 => 0,
+      _ => max(1, (tx_ms as f64 * send_fraction) as u64)
+    };
+    (send_ms, (rx_ms as f64 * grace) as u64)
+  }
+
+  pub fn start_session_with_frame(&mut self, mut connect_frame: Frame) -> Result<(u32, u32)> {
+    // Neither CONNECT nor CONNECTED headers are escaped, whatever the version
+    connect_frame.headers.escape_for(StompVersion::Stomp_v1_0);
+    try!(connect_frame.write(&mut self.stream).map_err(handshake_error));
     let connected_frame : Frame;
-    let mut buffered_reader = BufReader::new(self.tcp_stream.try_clone().unwrap());
+    let mut buffered_reader = BufReader::new(&mut self.stream);
     loop{
-      let transmission = try!(Frame::read(&mut buffered_reader));
+      let transmission = try!(Frame::read(&mut buffered_reader).map_err(handshake_error));
       match transmission {
         Transmission::HeartBeat => continue,
         Transmission::CompleteFrame(frame) => {
           connected_frame = frame;
           break;
         },
+        Transmission::StreamingFrame(_) => return Err(Error::new(ErrorKind::InvalidData, "Unexpected streamed frame while waiting for CONNECTED frame.")),
         Transmission::ConnectionClosed => return Err(Error::new(ErrorKind::ConnectionAborted, "Connection closed by remote host while waiting for CONNECTED frame."))
       } 
     }
     match connected_frame.command.as_ref() {
       "CONNECTED" => debug!("Received CONNECTED frame: {}", connected_frame),
+      // e.g. bad credentials
+      "ERROR" => return Err(Error::new(ErrorKind::ConnectionRefused, BrokerError::from_frame(&connected_frame))),
        _ => return Err(Error::new(ErrorKind::InvalidInput, "Could not connect."))
     }
+    self.version = match connected_frame.headers.get_version() {
+      Some(header::Version(version)) => version,
+      None => StompVersion::Stomp_v1_0
+    };
+    self.server = connected_frame.headers.get_server().map(|header::Server(server)| server.to_string());
+    self.session_id = connected_frame.headers.get_session().map(|header::Session(session_id)| session_id.to_string());
     match connected_frame.headers.get_heart_beat() {
       Some(header::HeartBeat(tx_ms, rx_ms)) => Ok((tx_ms, rx_ms)),
       None => Ok((0, 0))
     }
   }
 }
+
+// Timeouts while setting up the session are reported as handshake timeouts
+pub fn handshake_error(error: Error) -> Error {
+  if is_timeout(&error) && TimeoutKind::of(&error).is_none() {
+    TimeoutKind::Handshake.error()
+  } else {
+    error
+  }
+}
+
+#[test]
+fn connects_over_a_unix_socket() {
+  use std::io::{Read, Write};
+  use std::os::unix::net::UnixListener;
+  use std::thread;
+  let path = ::std::env::temp_dir().join(format!("stomp-rs-{}.sock", ::std::process::id()));
+  let _ = ::std::fs::remove_file(&path);
+  let listener = UnixListener::bind(&path).unwrap();
+  let broker = thread::spawn(move || {
+    let (mut stream, _) = listener.accept().unwrap();
+    let mut connect_frame = Vec::new();
+    let mut byte = [0u8; 1];
+    while stream.read(&mut byte).unwrap() == 1 && byte[0] != 0 {
+      connect_frame.push(byte[0]);
+    }
+    stream.write_all(b"CONNECTED\nversion:1.2\nheart-beat:1000,2000\n\n\0").unwrap();
+    String::from_utf8(connect_frame).unwrap()
+  });
+  let mut connection = Connection::new_unix(path.to_str().unwrap(), Timeouts::default()).unwrap();
+  let mut headers = header::HeaderList::new();
+  headers.push(header::Header::new("accept-version", "1.2"));
+  let connect_frame = Frame {
+    command: "CONNECT".to_string(),
+    headers: headers,
+    body: Vec::new()
+  };
+  assert_eq!((1000, 2000), connection.start_session_with_frame(connect_frame).unwrap());
+  assert!(broker.join().unwrap().starts_with("CONNECT\n"));
+  let _ = ::std::fs::remove_file(&path);
+}
+
+#[test]
+fn silent_brokers_cause_a_handshake_timeout() {
+  use std::os::unix::net::UnixListener;
+  let path = ::std::env::temp_dir().join(format!("stomp-rs-silent-{}.sock", ::std::process::id()));
+  let _ = ::std::fs::remove_file(&path);
+  // The listener accepts connections through its backlog but never answers
+  let _listener = UnixListener::bind(&path).unwrap();
+  let timeouts = Timeouts {
+    handshake: Some(Duration::from_millis(50)),
+    ..Timeouts::default()
+  };
+  let mut connection = Connection::new_unix(path.to_str().unwrap(), timeouts).unwrap();
+  let connect_frame = Frame {
+    command: "CONNECT".to_string(),
+    headers: header::HeaderList::new(),
+    body: Vec::new()
+  };
+  let error = connection.start_session_with_frame(connect_frame).err().expect("Expected the handshake to time out.");
+  assert_eq!(ErrorKind::TimedOut, error.kind());
+  assert_eq!(Some(TimeoutKind::Handshake), TimeoutKind::of(&error));
+  let _ = ::std::fs::remove_file(&path);
+}
+
+#[test]
+fn ipv6_literals_resolve_with_or_without_brackets() {
+  let expected: SocketAddr = "[::1]:61613".parse().unwrap();
+  assert_eq!(vec![expected], resolve("::1", 61613).unwrap());
+  assert_eq!(vec![expected], resolve("[::1]", 61613).unwrap());
+}
+
+#[test]
+fn later_addresses_are_tried_when_earlier_ones_refuse() {
+  use std::net::TcpListener;
+  // Nothing listens on a port that was just released
+  let refused_address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let listening_address = listener.local_addr().unwrap();
+  let tcp_stream = connect_to_any(&[refused_address, listening_address], Some(Duration::from_secs(5))).unwrap();
+  assert_eq!(listening_address, tcp_stream.peer_addr().unwrap());
+}
+
+#[test]
+fn heartbeat_timers_apply_the_send_fraction_and_grace() {
+  assert_eq!((5000, 4000), Connection::heartbeat_timers((10000, 2000), DEFAULT_HEARTBEAT_SEND_FRACTION, DEFAULT_HEARTBEAT_GRACE));
+  assert_eq!((7500, 3000), Connection::heartbeat_timers((10000, 2000), 0.75, 1.5));
+  assert_eq!((0, 0), Connection::heartbeat_timers((0, 0), 0.75, 1.5));
+  assert_eq!((1, 0), Connection::heartbeat_timers((1, 0), 0.1, 1.5));
+}
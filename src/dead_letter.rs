@@ -0,0 +1,160 @@
+use std::collections::hash_map::HashMap;
+use std::collections::VecDeque;
+use frame::Frame;
+use header::{Header, HeaderList, StompHeaderSet};
+
+const DEFAULT_TRACKED_MESSAGES: usize = 10_000;
+
+// Headers that describe the original delivery rather than the message itself
+const DELIVERY_HEADERS: [&'static str; 7] = [
+  "destination", "message-id", "subscription", "ack", "content-length", "receipt", "transaction"
+];
+
+#[derive(Clone)]
+pub struct DeadLetter {
+  pub destination: String,
+  pub max_deliveries: u32,
+  pub key_header: String,
+  pub redelivery_count_header: Option<String>,
+  pub tracked_messages: usize
+}
+
+impl DeadLetter {
+  pub fn new(destination: &str, max_deliveries: u32) -> DeadLetter {
+    DeadLetter {
+      destination: destination.to_string(),
+      max_deliveries: max_deliveries,
+      key_header: "message-id".to_string(),
+      redelivery_count_header: None,
+      tracked_messages: DEFAULT_TRACKED_MESSAGES
+    }
+  }
+
+  // Trust a redelivery count supplied by the broker (e.g. RabbitMQ's 'x-delivery-count')
+  // instead of counting deliveries locally.
+  pub fn using_redelivery_count(mut self, header: &str) -> DeadLetter {
+    self.redelivery_count_header = Some(header.to_string());
+    self
+  }
+
+  pub fn keyed_by(mut self, header: &str) -> DeadLetter {
+    self.key_header = header.to_string();
+    self
+  }
+}
+
+pub struct RedeliveryTracker {
+  settings: DeadLetter,
+  deliveries: HashMap<String, u32>,
+  order: VecDeque<String>
+}
+
+impl RedeliveryTracker {
+  pub fn new(settings: DeadLetter) -> RedeliveryTracker {
+    RedeliveryTracker {
+      settings: settings,
+      deliveries: HashMap::new(),
+      order: VecDeque::new()
+    }
+  }
+
+  pub fn destination(&self) -> &str {
+    self.settings.destination.as_ref()
+  }
+
+  // Returns how many times this message has now been delivered, including this delivery.
+  pub fn record_delivery(&mut self, frame: &Frame) -> u32 {
+    if let Some(ref header) = self.settings.redelivery_count_header {
+      match frame.headers.get_header(header).and_then(|h| h.get_value().parse::<u32>().ok()) {
+        Some(redeliveries) => return redeliveries + 1,
+        None => debug!("Message had no usable '{}' header, counting deliveries locally.", header)
+      }
+    }
+    let key = match frame.headers.get_header(&self.settings.key_header) {
+      Some(header) => header.get_value().to_string(),
+      None => return 1
+    };
+    if !self.deliveries.contains_key(&key) {
+      self.order.push_back(key.clone());
+      while self.order.len() > self.settings.tracked_messages {
+        if let Some(oldest) = self.order.pop_front() {
+          self.deliveries.remove(&oldest);
+        }
+      }
+    }
+    let count = self.deliveries.entry(key).or_insert(0);
+    *count += 1;
+    *count
+  }
+
+  pub fn is_exhausted(&self, deliveries: u32) -> bool {
+    deliveries >= self.settings.max_deliveries
+  }
+
+  pub fn forget(&mut self, frame: &Frame) {
+    let key = match frame.headers.get_header(&self.settings.key_header) {
+      Some(header) => header.get_value(),
+      None => return
+    };
+    if self.deliveries.remove(key).is_some() {
+      self.order.retain(|tracked_key| tracked_key != key);
+    }
+  }
+
+  pub fn dead_letter_frame(&self, frame: &Frame, deliveries: u32) -> Frame {
+    let mut dead_letter_frame = Frame::send(self.destination(), frame.body.as_ref());
+    let mut headers = HeaderList::with_capacity(frame.headers.headers.len() + 4);
+    for header in frame.headers.iter().filter(|header| !DELIVERY_HEADERS.contains(&header.get_key())) {
+      headers.push(Header::new(header.get_key(), header.get_value()));
+    }
+    if let Some(destination) = frame.headers.get_destination() {
+      headers.push(Header::new("x-original-destination", destination.0));
+    }
+    if let Some(message_id) = frame.headers.get_message_id() {
+      headers.push(Header::new("x-original-message-id", message_id.0));
+    }
+    headers.push(Header::new("x-delivery-attempts", deliveries.to_string().as_ref()));
+    headers.push(Header::new("x-dead-letter-reason", "max-deliveries-exceeded"));
+    dead_letter_frame.headers.concat(&mut headers);
+    dead_letter_frame
+  }
+}
+
+#[cfg(test)]
+fn message(message_id: &str) -> Frame {
+  Frame {
+    command: "MESSAGE".to_string(),
+    headers: header_list![
+      "destination" => "/queue/orders",
+      "message-id" => message_id,
+      "subscription" => "stomp-rs/0",
+      "priority" => "4"
+    ],
+    body: "Modern major general".as_bytes().to_vec()
+  }
+}
+
+#[test]
+fn deliveries_are_counted_per_message() {
+  let mut tracker = RedeliveryTracker::new(DeadLetter::new("/queue/orders.dlq", 3));
+  assert_eq!(1, tracker.record_delivery(&message("1")));
+  assert_eq!(2, tracker.record_delivery(&message("1")));
+  assert_eq!(1, tracker.record_delivery(&message("2")));
+  let deliveries = tracker.record_delivery(&message("1"));
+  assert!(tracker.is_exhausted(deliveries));
+  tracker.forget(&message("1"));
+  assert_eq!(1, tracker.record_delivery(&message("1")));
+}
+
+#[test]
+fn dead_letter_frame_carries_error_metadata() {
+  let tracker = RedeliveryTracker::new(DeadLetter::new("/queue/orders.dlq", 3));
+  let frame = tracker.dead_letter_frame(&message("1"), 3);
+  assert_eq!("SEND", frame.command);
+  assert_eq!("/queue/orders.dlq", frame.headers.get_destination().unwrap().0);
+  assert_eq!("/queue/orders", frame.headers.get_header("x-original-destination").unwrap().get_value());
+  assert_eq!("3", frame.headers.get_header("x-delivery-attempts").unwrap().get_value());
+  assert_eq!("4", frame.headers.get_header("priority").unwrap().get_value());
+  assert!(frame.headers.get_subscription().is_none());
+  assert_eq!(frame.body, "Modern major general".as_bytes());
+}
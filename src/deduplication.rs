@@ -0,0 +1,146 @@
+use std::collections::hash_map::HashMap;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use frame::Frame;
+use header::StompHeaderSet;
+#[cfg(test)]
+use header::{Header, HeaderList};
+
+#[derive(Clone)]
+pub struct Deduplication {
+  pub header: String,
+  pub capacity: usize,
+  pub window: Option<Duration>
+}
+
+impl Deduplication {
+  pub fn by_message_id(capacity: usize) -> Deduplication {
+    Deduplication::by_header("message-id", capacity)
+  }
+
+  pub fn by_header(header: &str, capacity: usize) -> Deduplication {
+    Deduplication {
+      header: header.to_string(),
+      capacity: capacity,
+      window: None
+    }
+  }
+
+  pub fn within(mut self, window: Duration) -> Deduplication {
+    self.window = Some(window);
+    self
+  }
+}
+
+pub struct DuplicateFilter {
+  settings: Deduplication,
+  seen: HashMap<String, Instant>,
+  // Least recently seen keys are at the front
+  order: VecDeque<String>
+}
+
+impl DuplicateFilter {
+  pub fn new(settings: Deduplication) -> DuplicateFilter {
+    DuplicateFilter {
+      seen: HashMap::with_capacity(settings.capacity),
+      order: VecDeque::with_capacity(settings.capacity),
+      settings: settings
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.order.len()
+  }
+
+  pub fn is_duplicate(&mut self, frame: &Frame) -> bool {
+    self.expire(Instant::now());
+    match frame.headers.get_header(&self.settings.header) {
+      Some(header) => self.seen.contains_key(header.get_value()),
+      None => false
+    }
+  }
+
+  // Only messages that were acknowledged are remembered; a NACKed message is expected to come back.
+  pub fn remember(&mut self, frame: &Frame) {
+    let key = match frame.headers.get_header(&self.settings.header) {
+      Some(header) => header.get_value(),
+      None => return
+    };
+    if self.settings.capacity == 0 {
+      return;
+    }
+    if self.seen.insert(key.to_string(), Instant::now()).is_some() {
+      self.order.retain(|seen_key| seen_key != key);
+    }
+    self.order.push_back(key.to_string());
+    while self.order.len() > self.settings.capacity {
+      self.forget_oldest();
+    }
+  }
+
+  fn expire(&mut self, now: Instant) {
+    let window = match self.settings.window {
+      Some(window) => window,
+      None => return
+    };
+    loop {
+      let expired = match self.order.front() {
+        Some(key) => now.duration_since(self.seen[key]) > window,
+        None => false
+      };
+      if !expired {
+        break;
+      }
+      self.forget_oldest();
+    }
+  }
+
+  fn forget_oldest(&mut self) {
+    if let Some(key) = self.order.pop_front() {
+      self.seen.remove(&key);
+    }
+  }
+}
+
+#[cfg(test)]
+fn message_with_id(message_id: &str) -> Frame {
+  Frame {
+    command: "MESSAGE".to_string(),
+    headers: header_list![
+      "message-id" => message_id
+    ],
+    body: Vec::new()
+  }
+}
+
+#[test]
+fn remembered_messages_are_duplicates() {
+  let mut filter = DuplicateFilter::new(Deduplication::by_message_id(8));
+  let message = message_with_id("ID:broker-1");
+  assert!(!filter.is_duplicate(&message));
+  filter.remember(&message);
+  assert!(filter.is_duplicate(&message));
+  assert!(!filter.is_duplicate(&message_with_id("ID:broker-2")));
+}
+
+#[test]
+fn least_recently_seen_messages_are_evicted() {
+  let mut filter = DuplicateFilter::new(Deduplication::by_message_id(2));
+  filter.remember(&message_with_id("1"));
+  filter.remember(&message_with_id("2"));
+  filter.remember(&message_with_id("1"));
+  filter.remember(&message_with_id("3"));
+  assert_eq!(2, filter.len());
+  assert!(filter.is_duplicate(&message_with_id("1")));
+  assert!(!filter.is_duplicate(&message_with_id("2")));
+}
+
+#[test]
+fn messages_expire_after_the_window() {
+  let mut filter = DuplicateFilter::new(Deduplication::by_message_id(8).within(Duration::from_millis(0)));
+  let message = message_with_id("1");
+  filter.remember(&message);
+  ::std::thread::sleep(Duration::from_millis(2));
+  assert!(!filter.is_duplicate(&message));
+  assert_eq!(0, filter.len());
+}
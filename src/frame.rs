@@ -1,20 +1,33 @@
 use header::HeaderList;
 use header::Header;
+use header::HeaderCodec;
 use header::ContentLength;
 use header::StompHeaderSet;
+use header::StompVersion;
 use subscription::AckMode;
 use std::io::Result;
 use std::io::Error;
-use std::io::ErrorKind::InvalidInput;
+use std::io::ErrorKind::{InvalidInput, InvalidData};
 use std::io::Write;
+use std::io::BufWriter;
 use std::io::Read;
 use std::io::BufRead;
 use std::str::from_utf8;
 use std::fmt;
 use std::fmt::Formatter;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::cmp::min;
+
+const WRITE_BUFFER_SIZE: usize = 8 * 1024;
 
 pub trait ToFrameBody {
   fn to_frame_body<'a>(&'a self) -> &'a[u8];
+
+  // Owned bodies override this to hand over their buffer instead of copying it
+  fn into_frame_body(self) -> Vec<u8> where Self: Sized {
+    self.to_frame_body().to_vec()
+  }
 }
 
 impl <'b> ToFrameBody for &'b [u8] {
@@ -34,6 +47,100 @@ impl ToFrameBody for String {
     let string : &str = self.as_ref();
     string.as_bytes()
   }
+
+  fn into_frame_body(self) -> Vec<u8> {
+    self.into_bytes()
+  }
+}
+
+impl ToFrameBody for Vec<u8> {
+  fn to_frame_body<'a>(&'a self) -> &'a [u8] {
+    self.as_ref()
+  }
+
+  fn into_frame_body(self) -> Vec<u8> {
+    self
+  }
+}
+
+impl ToFrameBody for Box<[u8]> {
+  fn to_frame_body<'a>(&'a self) -> &'a [u8] {
+    self.as_ref()
+  }
+
+  fn into_frame_body(self) -> Vec<u8> {
+    self.into_vec()
+  }
+}
+
+// A reference-counted body that can be cloned cheaply and kept around, e.g. by application
+// code that hands the same payload to several parts of a program. Frames own their bodies,
+// so sending one copies it unless it is the last clone left.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SharedBody(Arc<Vec<u8>>);
+
+impl SharedBody {
+  pub fn new(body: Vec<u8>) -> SharedBody {
+    SharedBody(Arc::new(body))
+  }
+}
+
+impl Deref for SharedBody {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    self.0.as_ref()
+  }
+}
+
+impl From<Vec<u8>> for SharedBody {
+  fn from(body: Vec<u8>) -> SharedBody {
+    SharedBody::new(body)
+  }
+}
+
+impl From<String> for SharedBody {
+  fn from(body: String) -> SharedBody {
+    SharedBody::new(body.into_bytes())
+  }
+}
+
+impl ToFrameBody for SharedBody {
+  fn to_frame_body<'a>(&'a self) -> &'a [u8] {
+    self.0.as_ref()
+  }
+
+  fn into_frame_body(self) -> Vec<u8> {
+    match Arc::try_unwrap(self.0) {
+      Ok(body) => body,
+      Err(shared_body) => shared_body.as_ref().clone()
+    }
+  }
+}
+
+pub trait FromFrameBody: Sized {
+  fn from_frame_body(body: &[u8]) -> Result<Self>;
+}
+
+impl FromFrameBody for Vec<u8> {
+  fn from_frame_body(body: &[u8]) -> Result<Vec<u8>> {
+    Ok(body.to_vec())
+  }
+}
+
+impl FromFrameBody for String {
+  fn from_frame_body(body: &[u8]) -> Result<String> {
+    match from_utf8(body) {
+      Ok(string) => Ok(string.to_string()),
+      Err(_) => Err(Error::new(InvalidData, "Frame body was not valid utf8."))
+    }
+  }
+}
+
+impl FromFrameBody for SharedBody {
+  fn from_frame_body(body: &[u8]) -> Result<SharedBody> {
+    Ok(SharedBody::new(body.to_vec()))
+  }
 }
 
 #[derive(Clone)]
@@ -46,6 +153,8 @@ pub struct Frame {
 pub enum Transmission {
   HeartBeat,
   CompleteFrame(Frame),
+  // Only the command and headers have been parsed, the body follows on the connection
+  StreamingFrame(Frame),
   ConnectionClosed
 }
 
@@ -57,6 +166,10 @@ impl fmt::Display for Frame {
 
 impl Frame {
 
+  pub fn body_as<T: FromFrameBody>(&self) -> Result<T> {
+    T::from_frame_body(self.body.as_ref())
+  }
+
   pub fn count_bytes(&self) -> usize {
      let mut space_required : usize = 0;
     // Add one to space calculations to make room for '\n'
@@ -90,20 +203,37 @@ impl Frame {
 
   pub fn write<W: Write>(&self, stream: &mut W) -> Result<()> {
     debug!("Sending frame:\n{}", self.to_str());
-    try!(stream.write(self.command.as_bytes()));
-    try!(stream.write("\n".as_bytes()));
-    for header in self.headers.iter() {
-      try!(stream.write(header.get_raw().as_bytes()));
-      try!(stream.write("\n".as_bytes()));
-    }
-    try!(stream.write("\n".as_bytes()));
-    try!(stream.write_all(self.body.as_ref()));
-    try!(stream.write_all(&[0]));
-    try!(stream.flush());
+    // Small frames reach the stream in a single write; large bodies bypass the buffer
+    let capacity = min(self.count_bytes() + 1, WRITE_BUFFER_SIZE);
+    let mut buffered_writer = BufWriter::with_capacity(capacity, stream);
+    try!(self.write_unflushed(&mut buffered_writer));
+    try!(buffered_writer.flush());
     debug!("write() complete.");
     Ok(())
   }
 
+  pub fn encode(&self, buffer: &mut Vec<u8>) {
+    buffer.reserve(self.count_bytes() + 1);
+    self.write_unflushed(buffer).ok().expect("Writing to a Vec<u8> cannot fail.");
+  }
+
+  fn write_unflushed<W: Write>(&self, stream: &mut W) -> Result<()> {
+    try!(self.write_headers(stream));
+    try!(stream.write_all(self.body.as_ref()));
+    stream.write_all(&[0])
+  }
+
+  // Writes everything that precedes the body
+  pub fn write_headers<W: Write>(&self, stream: &mut W) -> Result<()> {
+    try!(stream.write_all(self.command.as_bytes()));
+    try!(stream.write_all("\n".as_bytes()));
+    for header in self.headers.iter() {
+      try!(stream.write_all(header.get_raw().as_bytes()));
+      try!(stream.write_all("\n".as_bytes()));
+    }
+    stream.write_all("\n".as_bytes())
+  }
+
   fn chomp_line(mut line: String) -> String {
     // This may be suboptimal, but fine for now
     let chomped_length : usize;
@@ -116,7 +246,10 @@ impl Frame {
     line
   }
 
+  // Used for the CONNECTED frame, whose headers are never escaped. Later frames go through
+  // the FrameBuffer, which decodes them for the negotiated version.
   pub fn read<B: BufRead>(stream: &mut B) -> Result<Transmission> {
+    let mut header_codec = HeaderCodec::for_version(StompVersion::Stomp_v1_0);
     let mut line : String = String::new();
     // Empty lines are interpreted as heartbeats
     let bytes: usize = try!(stream.read_line(&mut line));
@@ -138,7 +271,7 @@ impl Frame {
       if line.len() == 0 { // Empty line, no more headers
         break;
       }
-      let header = Header::decode(line.as_ref());
+      let header = header_codec.decode(line.as_ref());
       match header {
         Some(h) => header_list.push(h),
         None => return Err(Error::new(InvalidInput, "Invalid header encountered."))
@@ -232,14 +365,64 @@ impl Frame {
     nack_frame
   }
 
+  // ACK or NACK a MESSAGE frame the way the negotiated version identifies messages:
+  // 1.2 uses the 'ack' header, 1.1 the message-id and subscription, 1.0 only the message-id.
+  // Returns None if the message lacks the headers required.
+  pub fn settle_message(command: &str, message: &Frame, version: StompVersion) -> Option<Frame> {
+    let mut headers = HeaderList::with_capacity(2);
+    match version {
+      StompVersion::Stomp_v1_2 => {
+        let ack_id = match message.headers.get_ack() {
+          Some(::header::Ack(ack_id)) => ack_id,
+          None => return None
+        };
+        headers.push(Header::new("id", ack_id));
+      },
+      StompVersion::Stomp_v1_1 | StompVersion::Stomp_v1_0 => {
+        let message_id = match message.headers.get_message_id() {
+          Some(::header::MessageId(message_id)) => message_id,
+          None => return None
+        };
+        headers.push(Header::new("message-id", message_id));
+        if version == StompVersion::Stomp_v1_1 {
+          match message.headers.get_subscription() {
+            Some(::header::Subscription(subscription)) => headers.push(Header::new("subscription", subscription)),
+            None => return None
+          }
+        }
+      }
+    }
+    Some(Frame {
+      command: command.to_string(),
+      headers: headers,
+      body: Vec::new()
+    })
+  }
+
   pub fn send(destination: &str, body: &[u8]) -> Frame {
+    Frame::send_owned(destination, body.to_vec())
+  }
+
+  pub fn send_owned(destination: &str, body: Vec<u8>) -> Frame {
     let send_frame = Frame {
       command : "SEND".to_string(),
       headers : header_list![
         "destination" => destination,
         "content-length" => body.len().to_string().as_ref()
       ],
-      body : body.to_vec()
+      body : body
+    };
+    send_frame
+  }
+
+  pub fn send_streamed(destination: &str, content_length: u64) -> Frame {
+    let send_frame = Frame {
+      command : "SEND".to_string(),
+      headers : header_list![
+        "destination" => destination,
+        "content-length" => content_length.to_string().as_ref()
+      ],
+      body : Vec::new()
     };
     send_frame
   }
@@ -277,3 +460,42 @@ impl Frame {
     commit_frame 
   }
 }
+
+#[test]
+fn owned_bodies_are_moved_into_frames() {
+  let body = "Modern major general".to_string().into_bytes();
+  let body_pointer = body.as_ptr();
+  let frame = Frame::send_owned("/queue/a", body.into_frame_body());
+  assert_eq!(body_pointer, frame.body.as_ptr());
+  assert_eq!("Modern major general", frame.body_as::<String>().unwrap());
+
+  let shared_body = SharedBody::from("Animal".to_string());
+  let copy = shared_body.clone();
+  assert_eq!(&b"Animal"[..], &copy.into_frame_body()[..]);
+  let shared_pointer = shared_body.as_ptr();
+  assert_eq!(shared_pointer, shared_body.into_frame_body().as_ptr());
+}
+
+#[test]
+fn invalid_utf8_bodies_cannot_be_read_as_strings() {
+  let frame = Frame::send_owned("/queue/a", vec![0xff, 0xfe]);
+  assert!(frame.body_as::<String>().is_err());
+  assert_eq!(vec![0xff, 0xfe], frame.body_as::<Vec<u8>>().unwrap());
+}
+
+#[test]
+fn acknowledgements_follow_the_negotiated_version() {
+  let message = Frame {
+    command: "MESSAGE".to_string(),
+    headers: header_list![
+      "message-id" => "m-1",
+      "subscription" => "s-1",
+      "ack" => "a-1"
+    ],
+    body: Vec::new()
+  };
+  let header_text = |frame: Frame| frame.headers.iter().map(|header| header.get_raw().to_string()).collect::<Vec<String>>();
+  assert_eq!(vec!["id:a-1"], header_text(Frame::settle_message("ACK", &message, StompVersion::Stomp_v1_2).unwrap()));
+  assert_eq!(vec!["message-id:m-1", "subscription:s-1"], header_text(Frame::settle_message("NACK", &message, StompVersion::Stomp_v1_1).unwrap()));
+  assert_eq!(vec!["message-id:m-1"], header_text(Frame::settle_message("ACK", &message, StompVersion::Stomp_v1_0).unwrap()));
+}
@@ -2,12 +2,14 @@ use header::HeaderList;
 use header::Header;
 use header::ContentLength;
 use header::StompHeaderSet;
-use header::HeaderCodec;
+use header::{HeaderCodec, StompVersion};
 use std::str::from_utf8;
 use std::mem;
 use frame::{Frame, Transmission};
 use lifeguard::Pool;
 use std::collections::VecDeque;
+use std::cmp::min;
+use compression::ContentEncoding;
 
 const DEFAULT_STRING_POOL_SIZE: usize = 4;
 const DEFAULT_HEADER_CODEC_STRING_POOL_SIZE: usize = 16;
@@ -16,13 +18,16 @@ pub struct FrameBuffer {
   buffer: VecDeque<u8>, 
   parse_state: ParseState,
   string_pool: Pool<String>,
-  header_codec: HeaderCodec
+  header_codec: HeaderCodec,
+  streaming_threshold: Option<usize>
 }
 
 struct ParseState {
   command: Option<String>,
   headers: HeaderList,
   section: FrameSection,
+  // Body bytes (plus the null octet) of a streamed frame that haven't been consumed yet
+  streamed_bytes_remaining: usize
 }
 
 impl ParseState {
@@ -30,7 +35,8 @@ impl ParseState {
     ParseState {
       command: None,
       headers: header_list![],
-      section: FrameSection::Command
+      section: FrameSection::Command,
+      streamed_bytes_remaining: 0
     }
   }
 }
@@ -38,7 +44,8 @@ impl ParseState {
 enum FrameSection {
   Command,
   Headers,
-  Body
+  Body,
+  StreamedBody
 }
 
 enum ReadCommandResult {
@@ -68,10 +75,22 @@ impl FrameBuffer {
       buffer: VecDeque::with_capacity(capacity),
       parse_state: ParseState::new(),
       string_pool: Pool::with_size(DEFAULT_STRING_POOL_SIZE),
-      header_codec: HeaderCodec::with_pool_size(DEFAULT_HEADER_CODEC_STRING_POOL_SIZE)
+      header_codec: HeaderCodec::with_pool_size(DEFAULT_HEADER_CODEC_STRING_POOL_SIZE),
+      streaming_threshold: None
     }
   }
 
+  // MESSAGE frames with a content-length of at least `threshold` are handed out as soon as
+  // their headers are parsed; their bodies are then consumed separately.
+  pub fn stream_bodies_over(&mut self, threshold: Option<usize>) {
+    self.streaming_threshold = threshold;
+  }
+
+  // Header escaping differs between versions of the protocol
+  pub fn set_version(&mut self, version: StompVersion) {
+    self.header_codec.set_version(version);
+  }
+
   pub fn len(&self) -> usize {
     self.buffer.len()
   }
@@ -92,7 +111,51 @@ impl FrameBuffer {
     match self.parse_state.section {
       FrameSection::Command => self.resume_parsing_at_command(), 
       FrameSection::Headers => self.resume_parsing_at_headers(),
-      FrameSection::Body    => self.resume_parsing_at_body()
+      FrameSection::Body    => self.resume_parsing_at_body(),
+      FrameSection::StreamedBody => {
+        // Nobody consumed the body, so discard it as it arrives
+        self.skip_streamed_body();
+        if self.streamed_bytes_remaining() > 0 {
+          return None;
+        }
+        self.resume_parsing_at_command()
+      }
+    }
+  }
+
+  pub fn streamed_bytes_remaining(&self) -> usize {
+    match self.parse_state.section {
+      FrameSection::StreamedBody => self.parse_state.streamed_bytes_remaining,
+      _ => 0
+    }
+  }
+
+  // Copies already-buffered body bytes of a streamed frame, never the trailing null octet
+  pub fn read_streamed_body(&mut self, destination: &mut [u8]) -> usize {
+    let content_remaining = self.streamed_bytes_remaining().saturating_sub(1);
+    let count = min(min(destination.len(), content_remaining), self.buffer.len());
+    for (index, byte) in self.buffer.drain(..count).enumerate() {
+      destination[index] = byte;
+    }
+    self.consume_streamed_bytes(count);
+    count
+  }
+
+  pub fn skip_streamed_body(&mut self) {
+    let count = min(self.streamed_bytes_remaining(), self.buffer.len());
+    self.buffer.drain(..count);
+    self.consume_streamed_bytes(count);
+  }
+
+  // Accounts for body bytes that were read from the connection without passing through the buffer
+  pub fn consume_streamed_bytes(&mut self, count: usize) {
+    if count == 0 {
+      return;
+    }
+    self.parse_state.streamed_bytes_remaining -= count;
+    if self.parse_state.streamed_bytes_remaining == 0 {
+      debug!("Streamed body fully consumed.");
+      self.reset_parse_state();
     }
   }
 
@@ -128,20 +191,16 @@ impl FrameBuffer {
 //TODO: Eliminate clone()s
   fn resume_parsing_at_body(&mut self) -> Option<Transmission> {
     debug!("Parsing body.");
+    if let Some(content_length) = self.streamed_content_length() {
+      debug!("Streaming a body of {} bytes.", content_length);
+      let frame = self.take_frame(Vec::new());
+      self.parse_state.section = FrameSection::StreamedBody;
+      self.parse_state.streamed_bytes_remaining = content_length + 1; // null octet
+      return Some(Transmission::StreamingFrame(frame));
+    }
     match self.read_body() {
       ReadBodyResult::Body(body_bytes) => {
-        let command = match self.parse_state.command.take() {
-          Some(command) => command,
-          None => panic!("No COMMAND found.")
-        };
-        // Consider making the HeaderList an Option<HeaderList> to allow recycling
-        let headers = mem::replace(&mut self.parse_state.headers, header_list![]);
-        let body = body_bytes;
-        let frame = Frame {
-          command: command,
-          headers: headers,
-          body: body
-        };
+        let frame = self.take_frame(body_bytes);
         self.reset_parse_state();
         Some(Transmission::CompleteFrame(frame))
       },
@@ -149,6 +208,42 @@ impl FrameBuffer {
     }
   }
 
+  fn take_frame(&mut self, body: Vec<u8>) -> Frame {
+    let command = match self.parse_state.command.take() {
+      Some(command) => command,
+      None => panic!("No COMMAND found.")
+    };
+    // Consider making the HeaderList an Option<HeaderList> to allow recycling
+    let headers = mem::replace(&mut self.parse_state.headers, header_list![]);
+    Frame {
+      command: command,
+      headers: headers,
+      body: body
+    }
+  }
+
+  fn streamed_content_length(&self) -> Option<usize> {
+    let threshold = match self.streaming_threshold {
+      Some(threshold) => threshold,
+      None => return None
+    };
+    match self.parse_state.command {
+      Some(ref command) if command == "MESSAGE" => {},
+      _ => return None
+    }
+    // Encoded bodies have to be buffered so they can be decoded before delivery
+    let encoded = self.parse_state.headers.get_header("content-encoding")
+      .and_then(|header| ContentEncoding::from_header_value(header.get_value()))
+      .is_some();
+    if encoded {
+      return None;
+    }
+    match self.parse_state.headers.get_content_length() {
+      Some(ContentLength(num_bytes)) if num_bytes as usize >= threshold => Some(num_bytes as usize),
+      _ => None
+    }
+  }
+
   fn reset_parse_state(&mut self) {
     self.parse_state.section = FrameSection::Command;
   }
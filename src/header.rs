@@ -2,6 +2,7 @@
 #![macro_use]
 #![allow(non_camel_case_types)]
 use std::slice::Iter;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use unicode_segmentation::UnicodeSegmentation;
 use lifeguard::Pool;
 
@@ -51,6 +52,46 @@ impl HeaderList {
   pub fn retain<F>(&mut self, test: F) where F : Fn(&Header)->bool {
     self.headers.retain(test)
   }
+
+  // Outgoing headers are escaped for STOMP 1.2 when they are created. This re-escapes
+  // them for the version that was actually negotiated.
+  pub fn escape_for(&mut self, version: StompVersion) {
+    if version == StompVersion::Stomp_v1_2 {
+      return;
+    }
+    let mut header_codec = HeaderCodec::for_version(version);
+    for header in self.headers.iter_mut() {
+      let key = unescape_leniently(header.get_key());
+      let value = unescape_leniently(header.get_value());
+      *header = header_codec.encode_key_value(&key, &value);
+    }
+  }
+}
+
+// Unlike HeaderCodec::decode_value, leaves unknown escape sequences alone,
+// since headers copied from incoming frames may not be escaped at all
+fn unescape_leniently(value: &str) -> String {
+  let mut unescaped = String::with_capacity(value.len());
+  let mut characters = value.chars().peekable();
+  while let Some(character) = characters.next() {
+    if character != '\\' {
+      unescaped.push(character);
+      continue;
+    }
+    let replacement = match characters.peek() {
+      Some(&'c') => ':',
+      Some(&'r') => '\r',
+      Some(&'n') => '\n',
+      Some(&'\\') => '\\',
+      _ => {
+        unescaped.push(character);
+        continue;
+      }
+    };
+    characters.next();
+    unescaped.push(replacement);
+  }
+  unescaped
 }
 
 pub struct SuppressedHeader<'a> (pub &'a str);
@@ -62,7 +103,9 @@ pub struct Header {
 }
 
 pub struct HeaderCodec {
-  strings : Pool<String>
+  strings : Pool<String>,
+  // Decides which characters are escaped
+  version : StompVersion
 }
 
 impl HeaderCodec {
@@ -77,10 +120,21 @@ impl HeaderCodec {
 
   pub fn with_pool_size(size: usize) -> HeaderCodec {
     HeaderCodec {
-      strings: Pool::with_size(size)
+      strings: Pool::with_size(size),
+      version: StompVersion::Stomp_v1_2
     }
   }
 
+  pub fn for_version(version: StompVersion) -> HeaderCodec {
+    let mut header_codec = HeaderCodec::new();
+    header_codec.set_version(version);
+    header_codec
+  }
+
+  pub fn set_version(&mut self, version: StompVersion) {
+    self.version = version;
+  }
+
   pub fn encode_key_value(&mut self, key: &str, value: &str) -> Header {
     //let raw_string = format!("{}:{}", key, Header::encode_value(value));
     let mut raw_string = self.encode_value(key);
@@ -96,9 +150,16 @@ impl HeaderCodec {
 
   fn encode_value(&mut self, value: &str) -> String {
     let mut encoded = self.strings.detached();
+    // STOMP 1.0 has no escaping at all
+    if self.version == StompVersion::Stomp_v1_0 {
+      encoded.push_str(value);
+      return encoded;
+    }
     for grapheme in UnicodeSegmentation::graphemes(value, true) {
       match grapheme {
         "\\" => encoded.push_str(r"\\"),// Order is significant
+        // Carriage returns are only escaped from 1.2 on
+        "\r" if self.version == StompVersion::Stomp_v1_1 => encoded.push_str("\r"),
         "\r" => encoded.push_str(r"\r"),
         "\n" => encoded.push_str(r"\n"),
         ":" => encoded.push_str(r"\c"),
@@ -143,6 +204,10 @@ impl HeaderCodec {
   fn decode_value(&mut self, value: &str) -> String {
     let mut is_escaped = false;
     let mut decoded = self.strings.detached(); 
+    if self.version == StompVersion::Stomp_v1_0 {
+      decoded.push_str(value);
+      return decoded;
+    }
     for grapheme in UnicodeSegmentation::graphemes(value, true) {
       if !is_escaped {
         match grapheme {
@@ -232,13 +297,49 @@ pub struct Transaction<'a>(pub &'a str);
 #[derive(Clone, Copy)]
 pub struct Version(pub StompVersion);
 
+// Message properties understood by JMS-based brokers
+#[derive(Clone, Copy)]
+pub struct Persistent(pub bool);
+// 0 (lowest) to 9 (highest)
+#[derive(Clone, Copy)]
+pub struct Priority(pub u8);
+#[derive(Clone, Copy)]
+pub struct Expires(pub SystemTime);
+// Sets 'expires' relative to the moment the option is applied
 #[derive(Clone, Copy)]
+pub struct TimeToLive(pub Duration);
+pub struct CorrelationId<'a>(pub &'a str);
+pub struct ReplyTo<'a>(pub &'a str);
+pub struct MessageType<'a>(pub &'a str);
+
+pub const MAX_PRIORITY: u8 = 9;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub enum StompVersion {
   Stomp_v1_0,
   Stomp_v1_1,
   Stomp_v1_2,
 }
 
+impl StompVersion {
+  pub fn from_str(version: &str) -> Option<StompVersion> {
+    match version.trim() {
+      "1.0" => Some(StompVersion::Stomp_v1_0),
+      "1.1" => Some(StompVersion::Stomp_v1_1),
+      "1.2" => Some(StompVersion::Stomp_v1_2),
+      _ => None
+    }
+  }
+
+  pub fn as_str(&self) -> &'static str {
+    match *self {
+      StompVersion::Stomp_v1_0 => "1.0",
+      StompVersion::Stomp_v1_1 => "1.1",
+      StompVersion::Stomp_v1_2 => "1.2"
+    }
+  }
+}
+
 pub trait StompHeaderSet {
   fn get_content_length(&self) -> Option<ContentLength>;
   fn get_header<'a>(&'a self, key: &str) -> Option<&'a Header>;
@@ -258,6 +359,12 @@ pub trait StompHeaderSet {
   fn get_subscription<'a>(&'a self) -> Option<Subscription<'a>>;
   fn get_transaction<'a>(&'a self) -> Option<Transaction<'a>>;
   fn get_version(&self) -> Option<Version>;
+  fn get_persistent(&self) -> Option<Persistent>;
+  fn get_priority(&self) -> Option<Priority>;
+  fn get_expires(&self) -> Option<Expires>;
+  fn get_correlation_id<'a>(&'a self) -> Option<CorrelationId<'a>>;
+  fn get_reply_to<'a>(&'a self) -> Option<ReplyTo<'a>>;
+  fn get_type<'a>(&'a self) -> Option<MessageType<'a>>;
 }
 
 impl StompHeaderSet for HeaderList {
@@ -276,12 +383,7 @@ impl StompHeaderSet for HeaderList {
       Some(h) => h.get_value(),
       None => return None
     };
-    let versions: Vec<StompVersion> = versions.split(',').filter_map(|v| match v.trim() {
-      "1.0" => Some(StompVersion::Stomp_v1_0),
-      "1.1" => Some(StompVersion::Stomp_v1_1),
-      "1.2" => Some(StompVersion::Stomp_v1_2),
-      _ => None
-    }).collect();
+    let versions: Vec<StompVersion> = versions.split(',').filter_map(StompVersion::from_str).collect();
     Some(versions)
   }
 
@@ -394,12 +496,7 @@ impl StompHeaderSet for HeaderList {
       Some(h) => h.get_value(),
       None => return None
     };
-    match (version).as_ref() {
-      "1.0" => Some(Version(StompVersion::Stomp_v1_0)), // TODO: Impl FromStr for StompVersion
-      "1.1" => Some(Version(StompVersion::Stomp_v1_1)),
-      "1.2" => Some(Version(StompVersion::Stomp_v1_2)),
-      _ => None
-    }
+    StompVersion::from_str(version).map(Version)
   }
 
   fn get_content_length(&self) -> Option<ContentLength> {
@@ -413,6 +510,58 @@ impl StompHeaderSet for HeaderList {
     }
   }
 
+  fn get_persistent(&self) -> Option<Persistent> {
+    match self.get_header("persistent") {
+      Some(h) if h.get_value() == "true" => Some(Persistent(true)),
+      Some(h) if h.get_value() == "false" => Some(Persistent(false)),
+      _ => None
+    }
+  }
+
+  fn get_priority(&self) -> Option<Priority> {
+    let priority = match self.get_header("priority") {
+      Some(h) => h.get_value(),
+      None => return None
+    };
+    match priority.parse::<u8>().ok() {
+      Some(p) if p <= MAX_PRIORITY => Some(Priority(p)),
+      _ => None
+    }
+  }
+
+  fn get_expires(&self) -> Option<Expires> {
+    let expires = match self.get_header("expires") {
+      Some(h) => h.get_value(),
+      None => return None
+    };
+    match expires.parse::<u64>().ok() {
+      // An expiry of 0 means the message never expires
+      Some(0) | None => None,
+      Some(millis) => Some(Expires(UNIX_EPOCH + Duration::from_millis(millis)))
+    }
+  }
+
+  fn get_correlation_id<'a>(&'a self) -> Option<CorrelationId<'a>> {
+    match self.get_header("correlation-id"){
+      Some(h) => Some(CorrelationId(h.get_value())),
+      None => None
+    }
+  }
+
+  fn get_reply_to<'a>(&'a self) -> Option<ReplyTo<'a>> {
+    match self.get_header("reply-to"){
+      Some(h) => Some(ReplyTo(h.get_value())),
+      None => None
+    }
+  }
+
+  fn get_type<'a>(&'a self) -> Option<MessageType<'a>> {
+    match self.get_header("type"){
+      Some(h) => Some(MessageType(h.get_value())),
+      None => None
+    }
+  }
+
 
 }
 
@@ -486,3 +635,47 @@ fn decode_slash() {
   let encoded = r"Hello\\World";
   assert!(unencoded == Header::decode_value(encoded));
 }
+
+#[test]
+fn message_properties_are_parsed() {
+  let headers = header_list![
+    "persistent" => "true",
+    "priority" => "7",
+    "expires" => "1500",
+    "correlation-id" => "order-12",
+    "reply-to" => "/temp-queue/replies"
+  ];
+  assert!(headers.get_persistent().unwrap().0);
+  assert_eq!(7, headers.get_priority().unwrap().0);
+  assert_eq!(UNIX_EPOCH + Duration::from_millis(1500), headers.get_expires().unwrap().0);
+  assert_eq!("order-12", headers.get_correlation_id().unwrap().0);
+  assert_eq!("/temp-queue/replies", headers.get_reply_to().unwrap().0);
+  assert!(headers.get_type().is_none());
+}
+
+#[test]
+fn out_of_range_message_properties_are_ignored() {
+  let headers = header_list![
+    "persistent" => "yes",
+    "priority" => "12",
+    "expires" => "0"
+  ];
+  assert!(headers.get_persistent().is_none());
+  assert!(headers.get_priority().is_none());
+  assert!(headers.get_expires().is_none());
+}
+
+#[test]
+fn escaping_follows_the_protocol_version() {
+  let mut headers = HeaderList::new();
+  headers.push(Header::new("reply-to", "a:b\r \n\\"));
+  let mut v1_1_headers = headers.clone();
+  v1_1_headers.escape_for(StompVersion::Stomp_v1_1);
+  assert_eq!("reply-to:a\\cb\r \\n\\\\", v1_1_headers.get_header("reply-to").unwrap().get_raw());
+  let mut v1_0_headers = headers.clone();
+  v1_0_headers.escape_for(StompVersion::Stomp_v1_0);
+  assert_eq!("reply-to:a:b\r \n\\", v1_0_headers.get_header("reply-to").unwrap().get_raw());
+
+  let decoded = HeaderCodec::for_version(StompVersion::Stomp_v1_0).decode("path:C:\\queue").unwrap();
+  assert_eq!("C:\\queue", decoded.get_value());
+}
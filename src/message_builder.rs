@@ -1,17 +1,30 @@
 use session::Session;
 use frame::Frame;
 use option_setter::OptionSetter;
+use std::io::Read;
 use std::io::Result;
+use std::io::Error;
+use std::io::ErrorKind::InvalidInput;
+use std::time::Duration;
 
 pub struct MessageBuilder <'a, 'session: 'a> {
   pub session: &'a mut Session<'session>,
-  pub frame: Frame
+  pub frame: Frame,
+  pub body_source: Option<(Box<Read + 'a>, u64)>,
+  // Set when the client has to hold the message back itself
+  pub delay: Option<Duration>
 }
 
 impl <'a, 'session> MessageBuilder <'a, 'session> {
   #[allow(dead_code)] 
   pub fn send(self) -> Result<()> {
-    self.session.send(self.frame)
+    match (self.body_source, self.delay) {
+      // The body is read while the frame is written, so it cannot be held back
+      (Some(_), Some(_)) => Err(Error::new(InvalidInput, "Streamed messages cannot be delayed by the client.")),
+      (Some((mut body, content_length)), None) => self.session.send_streamed(self.frame, &mut *body, content_length),
+      (None, Some(delay)) => self.session.send_later(self.frame, delay),
+      (None, None) => self.session.send(self.frame)
+    }
   }
 
   #[allow(dead_code)] 
@@ -1,10 +1,27 @@
 use message_builder::MessageBuilder;
 use session_builder::SessionBuilder;
 use subscription_builder::SubscriptionBuilder;
-use header::{Header, SuppressedHeader, ContentType};
-use connection::{HeartBeat, Credentials};
+use header::{Header, HeaderList, SuppressedHeader, ContentType, AcceptVersion};
+use header::{Persistent, Priority, Expires, TimeToLive, CorrelationId, ReplyTo, MessageType, MAX_PRIORITY};
+use std::time::{SystemTime, UNIX_EPOCH};
+use scheduling::{Broker, Delay, DeliverAt, duration_to_millis};
+use broker_error::BrokerErrorPolicy;
+use proxy::Proxy;
+#[cfg(feature = "tls")]
+use tls::Tls;
+#[cfg(feature = "websocket")]
+use websocket::WebSocket;
+#[cfg(feature = "prometheus")]
+use prometheus::MetricsEndpoint;
+use connection::{HeartBeat, HeartBeatSendFraction, HeartBeatGrace, Credentials, UnixSocket, ConnectTimeout, HandshakeTimeout, WriteTimeout};
 use subscription::AckMode;
 use session::{ToFrameHandler, ReceiptHandler};
+use outbox::PersistentOutbox;
+use outbound_queue::OutboundHighWaterMark;
+use streaming::StreamingThreshold;
+use compression::Compression;
+use deduplication::Deduplication;
+use dead_letter::DeadLetter;
 
 pub trait OptionSetter<T> {
   fn set_option(self, T) -> T;
@@ -33,6 +50,196 @@ impl <'a, 'b> OptionSetter<MessageBuilder<'a, 'b>> for ContentType<'a> {
   }
 }
 
+// Message properties may only appear once, so setting one replaces any earlier value
+fn replace_header(headers: &mut HeaderList, key: &str, value: &str) {
+  headers.retain(|header| header.get_key() != key);
+  headers.push(Header::new(key, value));
+}
+
+fn millis_since_epoch(time: SystemTime) -> Option<u64> {
+  match time.duration_since(UNIX_EPOCH) {
+    Ok(duration) => Some(duration_to_millis(duration)),
+    Err(_) => None
+  }
+}
+
+impl <'a, 'b> OptionSetter<MessageBuilder<'a, 'b>> for Persistent {
+  fn set_option(self, mut builder: MessageBuilder<'a, 'b>) -> MessageBuilder<'a, 'b> {
+    let Persistent(persistent) = self;
+    replace_header(&mut builder.frame.headers, "persistent", if persistent { "true" } else { "false" });
+    builder
+  }
+}
+
+impl <'a, 'b> OptionSetter<MessageBuilder<'a, 'b>> for Priority {
+  fn set_option(self, mut builder: MessageBuilder<'a, 'b>) -> MessageBuilder<'a, 'b> {
+    let Priority(mut priority) = self;
+    if priority > MAX_PRIORITY {
+      warn!("Priority {} is out of range, using {} instead.", priority, MAX_PRIORITY);
+      priority = MAX_PRIORITY;
+    }
+    replace_header(&mut builder.frame.headers, "priority", priority.to_string().as_ref());
+    builder
+  }
+}
+
+impl <'a, 'b> OptionSetter<MessageBuilder<'a, 'b>> for Expires {
+  fn set_option(self, mut builder: MessageBuilder<'a, 'b>) -> MessageBuilder<'a, 'b> {
+    let Expires(expiry_time) = self;
+    match millis_since_epoch(expiry_time) {
+      // 0 would tell the broker that the message never expires
+      Some(millis) if millis > 0 => replace_header(&mut builder.frame.headers, "expires", millis.to_string().as_ref()),
+      _ => warn!("Ignoring an expiry time that is not after the Unix epoch.")
+    }
+    builder
+  }
+}
+
+impl <'a, 'b> OptionSetter<MessageBuilder<'a, 'b>> for TimeToLive {
+  fn set_option(self, mut builder: MessageBuilder<'a, 'b>) -> MessageBuilder<'a, 'b> {
+    let TimeToLive(time_to_live) = self;
+    if time_to_live.as_secs() == 0 && time_to_live.subsec_nanos() == 0 {
+      // Like JMS, a time to live of zero means the message does not expire
+      builder.frame.headers.retain(|header| header.get_key() != "expires");
+      return builder;
+    }
+    Expires(SystemTime::now() + time_to_live).set_option(builder)
+  }
+}
+
+impl <'a, 'b, 'c> OptionSetter<MessageBuilder<'a, 'b>> for CorrelationId<'c> {
+  fn set_option(self, mut builder: MessageBuilder<'a, 'b>) -> MessageBuilder<'a, 'b> {
+    let CorrelationId(correlation_id) = self;
+    replace_header(&mut builder.frame.headers, "correlation-id", correlation_id);
+    builder
+  }
+}
+
+impl <'a, 'b, 'c> OptionSetter<MessageBuilder<'a, 'b>> for ReplyTo<'c> {
+  fn set_option(self, mut builder: MessageBuilder<'a, 'b>) -> MessageBuilder<'a, 'b> {
+    let ReplyTo(destination) = self;
+    replace_header(&mut builder.frame.headers, "reply-to", destination);
+    builder
+  }
+}
+
+impl <'a, 'b, 'c> OptionSetter<MessageBuilder<'a, 'b>> for MessageType<'c> {
+  fn set_option(self, mut builder: MessageBuilder<'a, 'b>) -> MessageBuilder<'a, 'b> {
+    let MessageType(message_type) = self;
+    replace_header(&mut builder.frame.headers, "type", message_type);
+    builder
+  }
+}
+
+impl <'a, 'b> OptionSetter<MessageBuilder<'a, 'b>> for Delay {
+  fn set_option(self, mut builder: MessageBuilder<'a, 'b>) -> MessageBuilder<'a, 'b> {
+    let Delay(delay) = self;
+    let delay_ms = duration_to_millis(delay).to_string();
+    match builder.session.broker() {
+      Broker::ActiveMq => replace_header(&mut builder.frame.headers, "AMQ_SCHEDULED_DELAY", delay_ms.as_ref()),
+      // Requires the delayed message exchange plugin
+      Broker::RabbitMq => replace_header(&mut builder.frame.headers, "x-delay", delay_ms.as_ref()),
+      Broker::Generic => builder.delay = Some(delay)
+    }
+    builder
+  }
+}
+
+impl <'a, 'b> OptionSetter<MessageBuilder<'a, 'b>> for DeliverAt {
+  fn set_option(self, builder: MessageBuilder<'a, 'b>) -> MessageBuilder<'a, 'b> {
+    Delay(self.delay()).set_option(builder)
+  }
+}
+
+#[cfg(feature = "tls")]
+impl <'a> OptionSetter<SessionBuilder<'a>> for Tls {
+  fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
+    builder.tls = Some(self);
+    builder
+  }
+}
+
+#[cfg(feature = "websocket")]
+impl <'a> OptionSetter<SessionBuilder<'a>> for WebSocket {
+  fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
+    builder.websocket = Some(self);
+    builder
+  }
+}
+
+#[cfg(feature = "prometheus")]
+impl <'a, 'b> OptionSetter<SessionBuilder<'a>> for MetricsEndpoint<'b> {
+  fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
+    let MetricsEndpoint(address) = self;
+    builder.metrics_endpoint = Some(address.to_string());
+    builder
+  }
+}
+
+impl <'a> OptionSetter<SessionBuilder<'a>> for ConnectTimeout {
+  fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
+    let ConnectTimeout(timeout) = self;
+    builder.timeouts.connect = Some(timeout);
+    builder
+  }
+}
+
+impl <'a> OptionSetter<SessionBuilder<'a>> for HandshakeTimeout {
+  fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
+    let HandshakeTimeout(timeout) = self;
+    builder.timeouts.handshake = Some(timeout);
+    builder
+  }
+}
+
+impl <'a> OptionSetter<SessionBuilder<'a>> for WriteTimeout {
+  fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
+    let WriteTimeout(timeout) = self;
+    builder.timeouts.write = Some(timeout);
+    builder
+  }
+}
+
+impl <'a> OptionSetter<SessionBuilder<'a>> for AcceptVersion {
+  fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
+    let AcceptVersion(versions) = self;
+    if versions.is_empty() {
+      warn!("Ignoring an empty list of accepted STOMP versions.");
+      return builder;
+    }
+    builder.accept_versions = versions;
+    builder
+  }
+}
+
+impl <'a> OptionSetter<SessionBuilder<'a>> for BrokerErrorPolicy {
+  fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
+    builder.broker_error_policy = self;
+    builder
+  }
+}
+
+impl <'a> OptionSetter<SessionBuilder<'a>> for Proxy {
+  fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
+    builder.proxy = Some(self);
+    builder
+  }
+}
+
+impl <'a> OptionSetter<SessionBuilder<'a>> for UnixSocket<'a> {
+  fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
+    builder.unix_socket = Some(self);
+    builder
+  }
+}
+
+impl <'a> OptionSetter<SessionBuilder<'a>> for Broker {
+  fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
+    builder.broker = self;
+    builder
+  }
+}
+
 impl <'a> OptionSetter<SessionBuilder<'a>> for Header {
   fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
     builder.headers.push(self);
@@ -47,6 +254,30 @@ impl <'a> OptionSetter<SessionBuilder<'a>> for HeartBeat {
   }
 }
 
+impl <'a> OptionSetter<SessionBuilder<'a>> for HeartBeatSendFraction {
+  fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
+    let HeartBeatSendFraction(fraction) = self;
+    if !(fraction > 0.0 && fraction <= 1.0) {
+      warn!("Ignoring heartbeat send fraction {}; it must be greater than 0 and at most 1.", fraction);
+      return builder;
+    }
+    builder.heartbeat_send_fraction = fraction;
+    builder
+  }
+}
+
+impl <'a> OptionSetter<SessionBuilder<'a>> for HeartBeatGrace {
+  fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
+    let HeartBeatGrace(grace) = self;
+    if !(grace >= 1.0) {
+      warn!("Ignoring heartbeat grace {}; it must be at least 1.", grace);
+      return builder;
+    }
+    builder.heartbeat_grace = grace;
+    builder
+  }
+}
+
 impl <'a> OptionSetter<SessionBuilder<'a>> for Credentials<'a> {
   fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
     builder.credentials = Some(self);
@@ -54,6 +285,36 @@ impl <'a> OptionSetter<SessionBuilder<'a>> for Credentials<'a> {
   }
 }
 
+impl <'a> OptionSetter<SessionBuilder<'a>> for PersistentOutbox<'a> {
+  fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
+    builder.outbox = Some(self);
+    builder
+  }
+}
+
+impl <'a> OptionSetter<SessionBuilder<'a>> for OutboundHighWaterMark {
+  fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
+    let OutboundHighWaterMark(bytes) = self;
+    builder.outbound_high_water_mark = Some(bytes);
+    builder
+  }
+}
+
+impl <'a> OptionSetter<SessionBuilder<'a>> for Compression {
+  fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
+    builder.compression = Some(self);
+    builder
+  }
+}
+
+impl <'a> OptionSetter<SessionBuilder<'a>> for StreamingThreshold {
+  fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
+    let StreamingThreshold(bytes) = self;
+    builder.streaming_threshold = Some(bytes);
+    builder
+  }
+}
+
 impl <'a> OptionSetter<SessionBuilder<'a>> for SuppressedHeader<'a> {
   fn set_option(self, mut builder: SessionBuilder<'a>) -> SessionBuilder<'a> {
     let SuppressedHeader(key) = self;
@@ -84,6 +345,20 @@ impl <'a, 'session, 'sub> OptionSetter<SubscriptionBuilder<'a, 'session, 'sub>>
   }
 }
 
+impl <'a, 'session, 'sub> OptionSetter<SubscriptionBuilder<'a, 'session, 'sub>> for Deduplication {
+  fn set_option(self, mut builder: SubscriptionBuilder<'a, 'session, 'sub>) -> SubscriptionBuilder<'a, 'session, 'sub> {
+    builder.deduplication = Some(self);
+    builder
+  }
+}
+
+impl <'a, 'session, 'sub> OptionSetter<SubscriptionBuilder<'a, 'session, 'sub>> for DeadLetter {
+  fn set_option(self, mut builder: SubscriptionBuilder<'a, 'session, 'sub>) -> SubscriptionBuilder<'a, 'session, 'sub> {
+    builder.dead_letter = Some(self);
+    builder
+  }
+}
+
 impl <'a, 'session, T> OptionSetter<MessageBuilder<'a, 'session>> for ReceiptHandler<'session, T> where T : ToFrameHandler<'session> {
   fn set_option(self, mut builder: MessageBuilder<'a, 'session>) -> MessageBuilder<'a, 'session> {
     let next_id = builder.session.generate_receipt_id();
@@ -108,3 +383,57 @@ impl <'a, 'session, 'sub, T> OptionSetter<SubscriptionBuilder<'a, 'session, 'sub
   }
 }
 
+
+#[cfg(test)]
+fn test_session<'a>() -> (::session::Session<'a>, ::std::os::unix::net::UnixStream) {
+  use std::io::Write;
+  let (client_end, mut broker_end) = ::std::os::unix::net::UnixStream::pair().unwrap();
+  broker_end.write_all(b"CONNECTED\nversion:1.2\n\n\0").unwrap();
+  let session = SessionBuilder::new("localhost", 61613).start_with_transport(Box::new(client_end)).unwrap();
+  (session, broker_end)
+}
+
+#[cfg(test)]
+fn header_values(headers: &HeaderList, key: &str) -> Vec<String> {
+  headers.iter()
+    .filter(|header| header.get_key() == key)
+    .map(|header| header.get_value().to_string())
+    .collect()
+}
+
+#[test]
+fn message_properties_replace_earlier_values() {
+  let (mut session, _broker_end) = test_session();
+  let builder = session.message("/queue/a", "Animal")
+    .with(Priority(3))
+    .with(Priority(12))
+    .with(Persistent(false))
+    .with(Persistent(true))
+    .with(CorrelationId("order-1"))
+    .with(CorrelationId("order-2"));
+  // Out of range priorities are clamped rather than sent as they are
+  assert_eq!(vec![MAX_PRIORITY.to_string()], header_values(&builder.frame.headers, "priority"));
+  assert_eq!(vec!["true"], header_values(&builder.frame.headers, "persistent"));
+  assert_eq!(vec!["order-2"], header_values(&builder.frame.headers, "correlation-id"));
+}
+
+#[test]
+fn expiry_is_sent_in_milliseconds_since_the_epoch() {
+  use std::time::Duration;
+  let (mut session, _broker_end) = test_session();
+  let builder = session.message("/queue/a", "Animal")
+    .with(Expires(UNIX_EPOCH + Duration::from_millis(1500)))
+    .with(Expires(UNIX_EPOCH));
+  // The epoch itself would mean that the message never expires, so it is ignored
+  assert_eq!(vec!["1500"], header_values(&builder.frame.headers, "expires"));
+
+  let before = duration_to_millis(SystemTime::now().duration_since(UNIX_EPOCH).unwrap());
+  let builder = builder.with(TimeToLive(Duration::from_secs(60)));
+  let expires = header_values(&builder.frame.headers, "expires");
+  assert_eq!(1, expires.len());
+  let expires: u64 = expires[0].parse().unwrap();
+  assert!(expires >= before + 60_000 && expires < before + 70_000);
+
+  let builder = builder.with(TimeToLive(Duration::from_secs(0)));
+  assert!(header_values(&builder.frame.headers, "expires").is_empty());
+}
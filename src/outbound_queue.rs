@@ -0,0 +1,259 @@
+use std::io::Write;
+use std::io::Result;
+use std::io::ErrorKind::{WouldBlock, Interrupted, WriteZero};
+use std::io::Error;
+use std::cmp;
+use std::collections::VecDeque;
+use std::mem;
+use frame::Frame;
+
+const DEFAULT_QUEUE_CAPACITY: usize = 64 * 1024;
+
+#[derive(Clone, Copy)]
+pub struct OutboundHighWaterMark(pub usize);
+
+pub struct OutboundQueue {
+  buffer: Vec<u8>,
+  // Bytes before this index have already been written to the stream
+  position: usize,
+  // Where each frame that has not been written completely starts and ends in `buffer`
+  frame_ranges: VecDeque<(usize, usize)>,
+  // Frames written completely since take_written_frames() was last called
+  written_frames: usize,
+  high_water_mark: usize,
+  warning_threshold: Option<usize>,
+  above_threshold: bool
+}
+
+impl OutboundQueue {
+  pub fn new(warning_threshold: Option<usize>) -> OutboundQueue {
+    OutboundQueue {
+      buffer: Vec::with_capacity(DEFAULT_QUEUE_CAPACITY),
+      position: 0,
+      frame_ranges: VecDeque::new(),
+      written_frames: 0,
+      high_water_mark: 0,
+      warning_threshold: warning_threshold,
+      above_threshold: false
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.buffer.len() - self.position
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn high_water_mark(&self) -> usize {
+    self.high_water_mark
+  }
+
+  // The number of frames that have not been written completely
+  pub fn frames_len(&self) -> usize {
+    self.frame_ranges.len()
+  }
+
+  pub fn take_written_frames(&mut self) -> usize {
+    mem::replace(&mut self.written_frames, 0)
+  }
+
+  pub fn push_frame(&mut self, frame: &Frame) {
+    let start = self.buffer.len();
+    frame.encode(&mut self.buffer);
+    self.frame_ranges.push_back((start, self.buffer.len()));
+    self.on_growth();
+  }
+
+  pub fn push_encoded_frame(&mut self, encoded_frame: &[u8]) {
+    let start = self.buffer.len();
+    self.buffer.extend_from_slice(encoded_frame);
+    self.frame_ranges.push_back((start, self.buffer.len()));
+    self.on_growth();
+  }
+
+  pub fn push_bytes(&mut self, bytes: &[u8]) {
+    self.buffer.extend_from_slice(bytes);
+    self.on_growth();
+  }
+
+  // Drops anything queued after the first `len` pending bytes
+  pub fn truncate(&mut self, len: usize) {
+    let end = self.position + len;
+    self.buffer.truncate(end);
+    while self.frame_ranges.back().map_or(false, |&(_, frame_end)| frame_end > end) {
+      self.frame_ranges.pop_back();
+    }
+  }
+
+  // Empties the queue, returning every frame that was not written completely in full, e.g. to
+  // send them again over a new connection
+  pub fn take_unwritten_frames(&mut self) -> Vec<Vec<u8>> {
+    let frames = self.frame_ranges.iter()
+      .map(|&(start, end)| self.buffer[start..end].to_vec())
+      .collect();
+    self.clear();
+    frames
+  }
+
+  pub fn clear(&mut self) {
+    self.buffer.clear();
+    self.frame_ranges.clear();
+    self.position = 0;
+    self.above_threshold = false;
+  }
+
+  // Writes as much as the stream will accept. Returns Ok(true) once the queue is empty and
+  // Ok(false) if the stream would have blocked with bytes still pending.
+  pub fn write_to<W: Write>(&mut self, stream: &mut W) -> Result<bool> {
+    while self.position < self.buffer.len() {
+      match stream.write(&self.buffer[self.position..]) {
+        Ok(0) => return Err(Error::new(WriteZero, "The stream did not accept any bytes.")),
+        Ok(bytes_written) => {
+          debug!("Wrote {} of {} queued bytes.", bytes_written, self.len());
+          self.position += bytes_written;
+          self.count_written_frames();
+        },
+        Err(ref error) if error.kind() == WouldBlock => {
+          debug!("Stream would block, {} bytes remain queued.", self.len());
+          self.compact();
+          return Ok(false);
+        },
+        Err(ref error) if error.kind() == Interrupted => continue,
+        Err(error) => return Err(error)
+      }
+    }
+    self.clear();
+    match stream.flush() {
+      Ok(_) => Ok(true),
+      Err(ref error) if error.kind() == WouldBlock => Ok(true),
+      Err(error) => Err(error)
+    }
+  }
+
+  fn count_written_frames(&mut self) {
+    while self.frame_ranges.front().map_or(false, |&(_, frame_end)| frame_end <= self.position) {
+      self.frame_ranges.pop_front();
+      self.written_frames += 1;
+    }
+  }
+
+  // Under constant backpressure the queue never empties, so written bytes have to be dropped
+  // along the way. Waiting until they make up half of the buffer keeps the copying linear.
+  // A frame that was written in part is kept whole, so it can still be taken out in full.
+  fn compact(&mut self) {
+    let end = self.frame_ranges.front().map_or(self.position, |&(start, _)| cmp::min(start, self.position));
+    if end >= DEFAULT_QUEUE_CAPACITY && end * 2 >= self.buffer.len() {
+      self.buffer.drain(..end);
+      for frame_range in self.frame_ranges.iter_mut() {
+        frame_range.0 -= end;
+        frame_range.1 -= end;
+      }
+      self.position -= end;
+    }
+  }
+
+  fn on_growth(&mut self) {
+    let pending = self.len();
+    if pending > self.high_water_mark {
+      self.high_water_mark = pending;
+    }
+    if let Some(threshold) = self.warning_threshold {
+      if pending > threshold && !self.above_threshold {
+        warn!("Outbound queue holds {} bytes, above the high-water mark of {} bytes.", pending, threshold);
+        self.above_threshold = true;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+struct TrickleWriter {
+  written: Vec<u8>,
+  accept: usize
+}
+
+#[cfg(test)]
+impl Write for TrickleWriter {
+  fn write(&mut self, bytes: &[u8]) -> Result<usize> {
+    if self.accept == 0 {
+      return Err(Error::new(WouldBlock, "Try again later."));
+    }
+    let count = ::std::cmp::min(self.accept, bytes.len());
+    self.written.extend_from_slice(&bytes[..count]);
+    self.accept -= count;
+    Ok(count)
+  }
+
+  fn flush(&mut self) -> Result<()> {
+    Ok(())
+  }
+}
+
+#[test]
+fn partial_writes_resume_where_they_left_off() {
+  let mut queue = OutboundQueue::new(None);
+  queue.push_bytes("SEND\n\nAnimal\0".as_bytes());
+  let mut stream = TrickleWriter { written: Vec::new(), accept: 5 };
+  assert!(!queue.write_to(&mut stream).unwrap());
+  assert_eq!(8, queue.len());
+  queue.push_bytes("\n".as_bytes());
+  stream.accept = 100;
+  assert!(queue.write_to(&mut stream).unwrap());
+  assert!(queue.is_empty());
+  assert_eq!(13, queue.high_water_mark());
+  assert_eq!("SEND\n\nAnimal\0\n".as_bytes(), &stream.written[..]);
+}
+
+#[test]
+fn written_bytes_are_released_under_backpressure() {
+  let mut queue = OutboundQueue::new(None);
+  queue.push_bytes(&vec![b'x'; 4 * DEFAULT_QUEUE_CAPACITY]);
+  let mut stream = TrickleWriter { written: Vec::new(), accept: 3 * DEFAULT_QUEUE_CAPACITY };
+  assert!(!queue.write_to(&mut stream).unwrap());
+  assert_eq!(DEFAULT_QUEUE_CAPACITY, queue.len());
+  assert_eq!(DEFAULT_QUEUE_CAPACITY, queue.buffer.len());
+  queue.push_bytes(b"y");
+  queue.truncate(DEFAULT_QUEUE_CAPACITY);
+  stream.accept = 2 * DEFAULT_QUEUE_CAPACITY;
+  assert!(queue.write_to(&mut stream).unwrap());
+  assert_eq!(4 * DEFAULT_QUEUE_CAPACITY, stream.written.len());
+}
+
+#[test]
+fn frames_count_as_written_once_their_last_byte_is() {
+  let mut queue = OutboundQueue::new(None);
+  queue.push_frame(&Frame::ack("message-1"));
+  queue.push_bytes("\n".as_bytes());
+  queue.push_frame(&Frame::ack("message-2"));
+  queue.push_frame(&Frame::ack("message-3"));
+  assert_eq!(3, queue.frames_len());
+  let mut stream = TrickleWriter { written: Vec::new(), accept: queue.len() / 2 };
+  assert!(!queue.write_to(&mut stream).unwrap());
+  assert_eq!(1, queue.take_written_frames());
+  assert_eq!(0, queue.take_written_frames());
+  queue.truncate(queue.len() - Frame::ack("message-3").count_bytes() - 1);
+  assert_eq!(1, queue.frames_len());
+  stream.accept = 100;
+  assert!(queue.write_to(&mut stream).unwrap());
+  assert_eq!(1, queue.take_written_frames());
+  assert_eq!(0, queue.frames_len());
+}
+
+#[test]
+fn unwritten_frames_are_taken_out_whole() {
+  let mut queue = OutboundQueue::new(None);
+  queue.push_frame(&Frame::send("/queue/a", "Animal".as_bytes()));
+  queue.push_bytes("\n".as_bytes());
+  queue.push_frame(&Frame::send("/queue/a", "Vegetable".as_bytes()));
+  let first_length = Frame::send("/queue/a", "Animal".as_bytes()).count_bytes();
+  let mut stream = TrickleWriter { written: Vec::new(), accept: first_length + 3 };
+  assert!(!queue.write_to(&mut stream).unwrap());
+  let frames = queue.take_unwritten_frames();
+  assert_eq!(1, frames.len());
+  let mut encoded_frame = Vec::new();
+  Frame::send("/queue/a", "Vegetable".as_bytes()).encode(&mut encoded_frame);
+  assert_eq!(encoded_frame, frames[0]);
+  assert!(queue.is_empty());
+}
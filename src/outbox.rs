@@ -0,0 +1,432 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Read;
+use std::io::Write;
+use std::io::Result;
+use std::io::Error;
+use std::io::ErrorKind::InvalidData;
+use std::path::{Path, PathBuf};
+use std::str::from_utf8;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use frame::{Frame, Transmission};
+use frame_buffer::FrameBuffer;
+use header::{Header, HeaderList, Receipt, StompHeaderSet};
+
+// The journal is a sequence of records:
+//   +<receipt-id> <length>[ @<due, in milliseconds since the epoch>]\n<length bytes of encoded frame>\n
+//   -<receipt-id>\n
+// A '+' record is written before a SEND frame goes out, a '-' record once its RECEIPT arrives.
+// A '+' record replaces any earlier one with the same receipt id. The journal is truncated once
+// nothing is pending, and rewritten with only the pending entries once most records are dead.
+// Delayed frames are recorded along with their due time when they are queued; if the process
+// dies before they are sent, they are held again until that time when the outbox is next opened.
+
+#[derive(Clone, Copy)]
+pub struct PersistentOutbox<'a>(pub &'a str);
+
+struct OutboxEntry {
+  receipt_id: String,
+  // Headers are escaped for STOMP 1.2, like those of newly created frames, and are
+  // escaped for the negotiated version whenever the frame is sent
+  frame: Frame,
+  // Set while the frame is recorded but not yet due, so not to be resent
+  due: Option<SystemTime>
+}
+
+pub struct Outbox {
+  path: PathBuf,
+  journal: File,
+  pending: Vec<OutboxEntry>,
+  // Number of records in the journal, to tell how many of them are dead
+  records: usize,
+  next_entry_id: u32
+}
+
+impl Outbox {
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<Outbox> {
+    let path = path.as_ref().to_path_buf();
+    let frames = try!(Outbox::replay(&path));
+    let now = SystemTime::now();
+    // Unconfirmed frames are given fresh receipt ids so they cannot collide with the
+    // ids handed out by this process. The journal is compacted as a side effect.
+    let mut pending = Vec::with_capacity(frames.len());
+    let mut next_entry_id = 0;
+    for (mut frame, due) in frames {
+      // Headers come back from the journal decoded, so they need to be escaped again
+      let mut headers = HeaderList::with_capacity(frame.headers.headers.len() + 1);
+      for header in frame.headers.iter().filter(|header| header.get_key() != "receipt") {
+        headers.push(Header::new(header.get_key(), header.get_value()));
+      }
+      frame.headers = headers;
+      let receipt_id = format!("outbox/{}", next_entry_id);
+      next_entry_id += 1;
+      frame.headers.push(Header::new("receipt", receipt_id.as_ref()));
+      pending.push(OutboxEntry {
+        receipt_id: receipt_id,
+        frame: frame,
+        // Frames that fell due while the process was down are sent straight away
+        due: due.and_then(|due| if due > now { Some(due) } else { None })
+      });
+    }
+    try!(Outbox::compact(&path, &pending));
+    let journal = try!(OpenOptions::new().append(true).create(true).open(&path));
+    if pending.len() > 0 {
+      info!("Outbox '{}' contains {} unconfirmed message(s).", path.display(), pending.len());
+    }
+    Ok(Outbox {
+      path: path,
+      journal: journal,
+      records: pending.len(),
+      pending: pending,
+      next_entry_id: next_entry_id
+    })
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  pub fn len(&self) -> usize {
+    self.pending.len()
+  }
+
+  pub fn contains(&self, receipt_id: &str) -> bool {
+    self.pending.iter().any(|entry| entry.receipt_id == receipt_id)
+  }
+
+  pub fn pending<'a>(&'a self) -> Vec<&'a Frame> {
+    self.pending.iter()
+      .filter(|entry| entry.due.is_none())
+      .map(|entry| &entry.frame)
+      .collect()
+  }
+
+  // Frames still waiting to be sent, e.g. those replayed from the journal by `open`
+  pub fn held<'a>(&'a self) -> Vec<(SystemTime, &'a Frame)> {
+    self.pending.iter()
+      .filter_map(|entry| entry.due.map(|due| (due, &entry.frame)))
+      .collect()
+  }
+
+  // Expects the frame's headers to still be escaped for STOMP 1.2
+  pub fn record(&mut self, frame: &mut Frame) -> Result<()> {
+    self.record_entry(frame, None)
+  }
+
+  // Records a frame that will be sent later; recording it again once it is sent releases it
+  pub fn hold(&mut self, frame: &mut Frame, due: SystemTime) -> Result<()> {
+    self.record_entry(frame, Some(due))
+  }
+
+  fn record_entry(&mut self, frame: &mut Frame, due: Option<SystemTime>) -> Result<()> {
+    if let Some(Receipt(receipt_id)) = frame.headers.get_receipt() {
+      if let Some(entry) = self.pending.iter_mut().find(|entry| entry.due.is_some() && entry.receipt_id == receipt_id) {
+        // Already in the journal, but the frame may have been compressed since. Recording
+        // it again without a due time keeps it from being held back after a restart.
+        let mut encoded_frame = Vec::new();
+        frame.encode(&mut encoded_frame);
+        try!(Outbox::write_entry(&mut self.journal, &receipt_id, &encoded_frame, None));
+        try!(self.journal.sync_data());
+        self.records += 1;
+        entry.frame = frame.clone();
+        entry.due = None;
+        debug!("Released '{}' from the outbox.", receipt_id);
+        return Ok(());
+      }
+    }
+    let receipt_id = match frame.headers.get_receipt() {
+      Some(Receipt(receipt_id)) => receipt_id.to_string(),
+      None => {
+        let receipt_id = format!("outbox/{}", self.next_entry_id);
+        self.next_entry_id += 1;
+        frame.headers.push(Header::new("receipt", receipt_id.as_ref()));
+        receipt_id
+      }
+    };
+    let mut encoded_frame = Vec::new();
+    frame.encode(&mut encoded_frame);
+    try!(Outbox::write_entry(&mut self.journal, &receipt_id, &encoded_frame, due));
+    try!(self.journal.sync_data());
+    self.records += 1;
+    debug!("Recorded '{}' in the outbox.", receipt_id);
+    self.pending.push(OutboxEntry {
+      receipt_id: receipt_id,
+      frame: frame.clone(),
+      due: due
+    });
+    Ok(())
+  }
+
+  pub fn confirm(&mut self, receipt_id: &str) -> Result<bool> {
+    let position = match self.pending.iter().position(|entry| entry.receipt_id == receipt_id) {
+      Some(position) => position,
+      None => return Ok(false)
+    };
+    self.pending.remove(position);
+    if self.pending.is_empty() {
+      // Nothing left to track, so the journal can start over
+      try!(self.journal.set_len(0));
+      self.records = 0;
+    } else if self.records - self.pending.len() >= self.pending.len() {
+      // Most of the journal is dead records by now, so it is rewritten with only the live ones
+      try!(Outbox::compact(&self.path, &self.pending));
+      self.journal = try!(OpenOptions::new().append(true).open(&self.path));
+      self.records = self.pending.len();
+    } else {
+      try!(write!(self.journal, "-{}\n", receipt_id));
+      self.records += 1;
+    }
+    try!(self.journal.sync_data());
+    debug!("Confirmed '{}', {} message(s) left in the outbox.", receipt_id, self.pending.len());
+    Ok(true)
+  }
+
+  // Forgets an entry the broker refused with an ERROR frame, so it is not resent
+  pub fn reject(&mut self, receipt_id: &str) -> Result<bool> {
+    let rejected = try!(self.confirm(receipt_id));
+    if rejected {
+      warn!("Removed '{}' from the outbox, since the broker refused it.", receipt_id);
+    }
+    Ok(rejected)
+  }
+
+  // Forgets every entry recorded after the first `len`, e.g. when a batch is discarded.
+  // Delayed frames were never part of the batch, so they are kept.
+  pub fn truncate(&mut self, len: usize) -> Result<()> {
+    let discarded: Vec<String> = self.pending.iter()
+      .skip(len)
+      .filter(|entry| entry.due.is_none())
+      .map(|entry| entry.receipt_id.clone())
+      .collect();
+    for receipt_id in discarded {
+      try!(self.confirm(&receipt_id));
+    }
+    Ok(())
+  }
+
+  fn write_entry<W: Write>(writer: &mut W, receipt_id: &str, encoded_frame: &[u8], due: Option<SystemTime>) -> Result<()> {
+    try!(write!(writer, "+{} {}", receipt_id, encoded_frame.len()));
+    if let Some(due) = due {
+      let since_epoch = due.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+      try!(write!(writer, " @{}", since_epoch.as_secs() * 1000 + since_epoch.subsec_nanos() as u64 / 1_000_000));
+    }
+    try!(writer.write_all("\n".as_bytes()));
+    try!(writer.write_all(encoded_frame));
+    writer.write_all("\n".as_bytes())
+  }
+
+  fn compact(path: &Path, pending: &Vec<OutboxEntry>) -> Result<()> {
+    let mut temporary_path = path.as_os_str().to_os_string();
+    temporary_path.push(".compact");
+    let temporary_path = PathBuf::from(temporary_path);
+    {
+      let mut file = try!(File::create(&temporary_path));
+      for entry in pending {
+        let mut encoded_frame = Vec::new();
+        entry.frame.encode(&mut encoded_frame);
+        try!(Outbox::write_entry(&mut file, &entry.receipt_id, &encoded_frame, entry.due));
+      }
+      try!(file.sync_all());
+    }
+    fs::rename(&temporary_path, path)
+  }
+
+  fn replay(path: &Path) -> Result<Vec<(Frame, Option<SystemTime>)>> {
+    let mut contents = Vec::new();
+    match File::open(path) {
+      Ok(mut file) => { try!(file.read_to_end(&mut contents)); },
+      Err(ref error) if error.kind() == ::std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(error) => return Err(error)
+    }
+
+    let mut entries: Vec<(String, Vec<u8>, Option<SystemTime>)> = Vec::new();
+    let mut position = 0;
+    while position < contents.len() {
+      let line_end = match contents[position..].iter().position(|byte| *byte == '\n' as u8) {
+        Some(index) => position + index,
+        None => {
+          warn!("Ignoring incomplete record at the end of outbox '{}'.", path.display());
+          break;
+        }
+      };
+      let line = try!(from_utf8(&contents[position + 1..line_end])
+        .map_err(|_| Error::new(InvalidData, "Outbox journal record was not utf8.")));
+      match contents[position] {
+        b'+' => {
+          // Records of delayed frames end with their due time
+          let (line, due) = match line.rfind(" @") {
+            Some(index) => match line[index + 2..].parse::<u64>() {
+              Ok(millis) => (&line[..index], Some(UNIX_EPOCH + Duration::from_millis(millis))),
+              Err(_) => return Err(Error::new(InvalidData, "Malformed outbox journal record."))
+            },
+            _ => (line, None)
+          };
+          let mut parts = line.rsplitn(2, ' ');
+          let length = parts.next().and_then(|length| length.parse::<usize>().ok());
+          let receipt_id = parts.next();
+          let (receipt_id, length) = match (receipt_id, length) {
+            (Some(receipt_id), Some(length)) => (receipt_id.to_string(), length),
+            _ => return Err(Error::new(InvalidData, "Malformed outbox journal record."))
+          };
+          let frame_start = line_end + 1;
+          if contents.len() < frame_start + length + 1 {
+            warn!("Ignoring incomplete record at the end of outbox '{}'.", path.display());
+            break;
+          }
+          // A later record for the same receipt id replaces the earlier one
+          entries.retain(|&(ref recorded_id, _, _)| *recorded_id != receipt_id);
+          entries.push((receipt_id, contents[frame_start..frame_start + length].to_vec(), due));
+          position = frame_start + length + 1;
+        },
+        b'-' => {
+          entries.retain(|&(ref receipt_id, _, _)| receipt_id != line);
+          position = line_end + 1;
+        },
+        _ => return Err(Error::new(InvalidData, "Malformed outbox journal record."))
+      }
+    }
+
+    let mut frames = Vec::with_capacity(entries.len());
+    for (_, encoded_frame, due) in entries {
+      let mut frame_buffer = FrameBuffer::new();
+      frame_buffer.append(&encoded_frame);
+      match frame_buffer.read_transmission() {
+        Some(Transmission::CompleteFrame(frame)) => frames.push((frame, due)),
+        _ => return Err(Error::new(InvalidData, "Outbox journal contained an invalid frame."))
+      }
+    }
+    Ok(frames)
+  }
+}
+
+#[cfg(test)]
+fn temporary_journal(name: &str) -> PathBuf {
+  let path = ::std::env::temp_dir().join(format!("stomp-rs-{}-{}.journal", name, ::std::process::id()));
+  let _ = fs::remove_file(&path);
+  path
+}
+
+#[test]
+fn unconfirmed_frames_survive_reopening() {
+  let path = temporary_journal("unconfirmed");
+  {
+    let mut outbox = Outbox::open(&path).unwrap();
+    outbox.record(&mut Frame::send("/queue/a", "Animal".as_bytes())).unwrap();
+    outbox.record(&mut Frame::send("/queue/a", "Vegetable".as_bytes())).unwrap();
+    assert!(outbox.confirm("outbox/0").unwrap());
+  }
+  let outbox = Outbox::open(&path).unwrap();
+  assert_eq!(1, outbox.len());
+  let mut encoded_frame = Vec::new();
+  outbox.pending()[0].encode(&mut encoded_frame);
+  let pending_frame = String::from_utf8_lossy(&encoded_frame);
+  assert!(pending_frame.contains("Vegetable"));
+  assert!(pending_frame.contains("receipt:outbox/0"));
+  let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn journal_is_compacted_once_mostly_dead() {
+  let path = temporary_journal("compaction");
+  let mut outbox = Outbox::open(&path).unwrap();
+  outbox.record(&mut Frame::send("/queue/a", "Animal".as_bytes())).unwrap();
+  for _ in 0..100 {
+    outbox.record(&mut Frame::send("/queue/a", "Vegetable".as_bytes())).unwrap();
+    let receipt_id = format!("outbox/{}", outbox.next_entry_id - 1);
+    assert!(outbox.confirm(&receipt_id).unwrap());
+  }
+  // The entry that is never confirmed keeps the journal from being truncated
+  assert!(outbox.records <= 3);
+  let journal_length = fs::metadata(&path).unwrap().len();
+  let mut encoded_frame = Vec::new();
+  outbox.pending()[0].encode(&mut encoded_frame);
+  assert!(journal_length < 3 * encoded_frame.len() as u64);
+  drop(outbox);
+  let outbox = Outbox::open(&path).unwrap();
+  assert_eq!(1, outbox.len());
+  assert_eq!(b"Animal", &outbox.pending()[0].body[..]);
+  let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn replayed_headers_can_be_escaped_for_any_version() {
+  use header::StompVersion;
+  let path = temporary_journal("escaping");
+  {
+    let mut outbox = Outbox::open(&path).unwrap();
+    let mut frame = Frame::send("/queue/a", "Animal".as_bytes());
+    frame.headers.push(Header::new("note", "a:b"));
+    outbox.record(&mut frame).unwrap();
+  }
+  let outbox = Outbox::open(&path).unwrap();
+  let mut frame = outbox.pending()[0].clone();
+  frame.headers.escape_for(StompVersion::Stomp_v1_0);
+  let mut encoded_frame = Vec::new();
+  frame.encode(&mut encoded_frame);
+  assert!(String::from_utf8_lossy(&encoded_frame).contains("\nnote:a:b\n"));
+  let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn incomplete_trailing_record_is_ignored() {
+  let path = temporary_journal("incomplete");
+  {
+    let mut outbox = Outbox::open(&path).unwrap();
+    outbox.record(&mut Frame::send("/queue/a", "Mineral".as_bytes())).unwrap();
+  }
+  {
+    let mut journal = OpenOptions::new().append(true).open(&path).unwrap();
+    journal.write_all("+outbox/1 500\nSEND\n".as_bytes()).unwrap();
+  }
+  let outbox = Outbox::open(&path).unwrap();
+  assert_eq!(1, outbox.len());
+  let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn held_frames_are_resent_only_once_released_or_reopened() {
+  let path = temporary_journal("held");
+  {
+    let mut outbox = Outbox::open(&path).unwrap();
+    let mut delayed = Frame::send("/queue/a", "Animal".as_bytes());
+    outbox.hold(&mut delayed, SystemTime::now() + Duration::from_secs(60)).unwrap();
+    outbox.record(&mut Frame::send("/queue/a", "Vegetable".as_bytes())).unwrap();
+    assert_eq!(1, outbox.pending().len());
+    outbox.truncate(0).unwrap();
+    assert_eq!(1, outbox.len());
+    assert_eq!(0, outbox.pending().len());
+    outbox.record(&mut delayed).unwrap();
+    assert_eq!(1, outbox.len());
+    assert_eq!(b"Animal", &outbox.pending()[0].body[..]);
+    outbox.hold(&mut Frame::send("/queue/a", "Mineral".as_bytes()), SystemTime::now()).unwrap();
+  }
+  // Both were due by now, and the released one has to be sent again either way
+  let outbox = Outbox::open(&path).unwrap();
+  assert_eq!(2, outbox.pending().len());
+  assert_eq!(0, outbox.held().len());
+  let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn held_frames_stay_held_until_due_after_reopening() {
+  let path = temporary_journal("due");
+  let due = UNIX_EPOCH + Duration::from_millis(duration_since_epoch_ms() + 60_000);
+  {
+    let mut outbox = Outbox::open(&path).unwrap();
+    outbox.hold(&mut Frame::send("/queue/a", "Animal".as_bytes()), due).unwrap();
+    outbox.record(&mut Frame::send("/queue/a", "Vegetable".as_bytes())).unwrap();
+  }
+  let outbox = Outbox::open(&path).unwrap();
+  assert_eq!(2, outbox.len());
+  assert_eq!(b"Vegetable", &outbox.pending()[0].body[..]);
+  let held = outbox.held();
+  assert_eq!(1, held.len());
+  assert_eq!(due, held[0].0);
+  assert_eq!(b"Animal", &held[0].1.body[..]);
+  let _ = fs::remove_file(&path);
+}
+
+#[cfg(test)]
+fn duration_since_epoch_ms() -> u64 {
+  let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+  since_epoch.as_secs() * 1000 + since_epoch.subsec_nanos() as u64 / 1_000_000
+}
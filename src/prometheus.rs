@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Write};
+use std::io::Result;
+use std::io::Error;
+use std::io::ErrorKind::{WouldBlock, TimedOut};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+use mio::Evented;
+use session::Session;
+use stats::{SessionStats, Traffic};
+
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+// Scrapes are answered on the event loop's thread, so a slow client may only stall it this long,
+// however it spreads its request out
+const SCRAPE_TIMEOUT_MS: u64 = 250;
+const CONTENT_TYPE: &'static str = "text/plain; version=0.0.4";
+
+// The local address to serve GET /metrics on while the session is listening
+#[derive(Clone, Copy)]
+pub struct MetricsEndpoint<'a>(pub &'a str);
+
+// Renders the session's counters and gauges in the Prometheus text exposition format
+pub fn render(session: &Session) -> String {
+  let mut output = String::new();
+  metric(&mut output, "stomp_connection_info", "gauge", "The broker the session is connected to.");
+  let _ = writeln!(output, "stomp_connection_info{{version=\"{}\",server=\"{}\",session=\"{}\"}} 1",
+    session.version().as_str(),
+    escape(session.server().unwrap_or("")),
+    escape(session.session_id().unwrap_or("")));
+  metric(&mut output, "stomp_listening", "gauge", "Whether the session is processing frames from the broker.");
+  let _ = writeln!(output, "stomp_listening {}", if session.is_listening() { 1 } else { 0 });
+  metric(&mut output, "stomp_outbound_queued_bytes", "gauge", "Bytes waiting to be written to the broker.");
+  let _ = writeln!(output, "stomp_outbound_queued_bytes {}", session.outbound_len());
+  render_stats(&session.stats(), &mut output);
+  output
+}
+
+pub fn render_stats(stats: &SessionStats, output: &mut String) {
+  counter(output, "stomp_reconnects_total", "Times the session reconnected to the broker.", stats.reconnects);
+  traffic(output, "stomp_frames_sent_total", "stomp_bytes_sent_total", "Frames sent", "command", &stats.sent);
+  traffic(output, "stomp_frames_received_total", "stomp_bytes_received_total", "Frames received", "command", &stats.received);
+  traffic(output, "stomp_destination_frames_sent_total", "stomp_destination_bytes_sent_total", "SEND frames", "destination", &stats.sent_per_destination);
+  traffic(output, "stomp_destination_frames_received_total", "stomp_destination_bytes_received_total", "MESSAGE frames", "destination", &stats.received_per_destination);
+  counter(output, "stomp_acks_total", "ACK frames sent.", stats.acks);
+  counter(output, "stomp_nacks_total", "NACK frames sent.", stats.nacks);
+  counter(output, "stomp_heartbeats_sent_total", "Heartbeats sent to the broker.", stats.heartbeats_sent);
+  counter(output, "stomp_heartbeats_received_total", "Heartbeats received from the broker.", stats.heartbeats_received);
+  counter(output, "stomp_heartbeats_missed_total", "Times the broker was silent for longer than the heartbeat grace period.", stats.heartbeats_missed);
+  metric(output, "stomp_outstanding_receipts", "gauge", "Receipts requested but not yet received.");
+  let _ = writeln!(output, "stomp_outstanding_receipts {}", stats.outstanding_receipts);
+  metric(output, "stomp_frame_buffer_high_water_mark_bytes", "gauge", "The most bytes the frame buffer has held.");
+  let _ = writeln!(output, "stomp_frame_buffer_high_water_mark_bytes {}", stats.frame_buffer_high_water_mark);
+
+  let histogram = &stats.receipt_latency;
+  metric(output, "stomp_receipt_latency_seconds", "histogram", "Time between requesting a receipt and receiving it.");
+  let mut cumulative = 0;
+  for (bound_ms, count) in histogram.bounds_ms.iter().zip(histogram.counts.iter()) {
+    cumulative += *count;
+    let _ = writeln!(output, "stomp_receipt_latency_seconds_bucket{{le=\"{}\"}} {}", *bound_ms as f64 / 1000.0, cumulative);
+  }
+  let _ = writeln!(output, "stomp_receipt_latency_seconds_bucket{{le=\"+Inf\"}} {}", histogram.count);
+  let _ = writeln!(output, "stomp_receipt_latency_seconds_sum {}", histogram.sum_ms as f64 / 1000.0);
+  let _ = writeln!(output, "stomp_receipt_latency_seconds_count {}", histogram.count);
+
+  let mut subscriptions: Vec<_> = stats.subscriptions.iter().collect();
+  subscriptions.sort_by(|a, b| a.0.cmp(b.0));
+  let subscription_counters: [(&str, &str, fn(&::stats::SubscriptionStats) -> u64); 4] = [
+    ("stomp_subscription_messages_total", "Messages delivered to the subscription.", |s| s.messages.frames),
+    ("stomp_subscription_bytes_total", "Bytes of messages delivered to the subscription.", |s| s.messages.bytes),
+    ("stomp_subscription_acks_total", "Messages the subscription ACKed.", |s| s.acks),
+    ("stomp_subscription_nacks_total", "Messages the subscription NACKed.", |s| s.nacks)
+  ];
+  for &(name, help, value) in subscription_counters.iter() {
+    metric(output, name, "counter", help);
+    for &(id, subscription) in subscriptions.iter() {
+      let _ = writeln!(output, "{}{{subscription=\"{}\",destination=\"{}\"}} {}", name, escape(id), escape(&subscription.destination), value(subscription));
+    }
+  }
+}
+
+fn metric(output: &mut String, name: &str, kind: &str, help: &str) {
+  let _ = writeln!(output, "# HELP {} {}", name, help);
+  let _ = writeln!(output, "# TYPE {} {}", name, kind);
+}
+
+fn counter(output: &mut String, name: &str, help: &str, value: u64) {
+  metric(output, name, "counter", help);
+  let _ = writeln!(output, "{} {}", name, value);
+}
+
+fn traffic(output: &mut String, frames_name: &str, bytes_name: &str, description: &str, label: &str, traffic: &HashMap<String, Traffic>) {
+  let mut entries: Vec<_> = traffic.iter().collect();
+  entries.sort_by(|a, b| a.0.cmp(b.0));
+  metric(output, frames_name, "counter", &format!("{} by {}.", description, label));
+  for &(key, counters) in entries.iter() {
+    let _ = writeln!(output, "{}{{{}=\"{}\"}} {}", frames_name, label, escape(key), counters.frames);
+  }
+  metric(output, bytes_name, "counter", &format!("Bytes of {} by {}.", description.to_lowercase(), label));
+  for &(key, counters) in entries.iter() {
+    let _ = writeln!(output, "{}{{{}=\"{}\"}} {}", bytes_name, label, escape(key), counters.bytes);
+  }
+}
+
+// Label values may not contain raw backslashes, quotes or newlines
+fn escape(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// Accepts scrapes on the session's event loop
+pub struct MetricsListener {
+  listener: TcpListener
+}
+
+impl MetricsListener {
+  pub fn bind(address: &str) -> Result<MetricsListener> {
+    let listener = try!(TcpListener::bind(address));
+    try!(listener.set_nonblocking(true));
+    info!("Serving metrics on http://{}/metrics", try!(listener.local_addr()));
+    Ok(MetricsListener { listener: listener })
+  }
+
+  // Answers a single scrape, so a burst of them cannot hold up the session for long. The
+  // listener is polled level-triggered, so the event loop comes back for the others.
+  pub fn serve(&self, session: &Session) {
+    let (stream, _) = match self.listener.accept() {
+      Ok(connection) => connection,
+      Err(ref error) if error.kind() == WouldBlock => return,
+      Err(error) => {
+        info!("Could not accept a metrics scrape: {}", error);
+        return;
+      }
+    };
+    let result = ScrapeStream::new(stream, Duration::from_millis(SCRAPE_TIMEOUT_MS))
+      .and_then(|mut scrape_stream| respond(&mut scrape_stream, session));
+    if let Err(error) = result {
+      debug!("Could not answer a metrics scrape: {}", error);
+    }
+  }
+}
+
+// Gives every read and write only the time that is left until the deadline
+struct ScrapeStream {
+  stream: TcpStream,
+  deadline: Instant
+}
+
+impl ScrapeStream {
+  fn new(stream: TcpStream, timeout: Duration) -> Result<ScrapeStream> {
+    try!(stream.set_nonblocking(false));
+    Ok(ScrapeStream {
+      stream: stream,
+      deadline: Instant::now() + timeout
+    })
+  }
+
+  fn remaining(&self) -> Result<Option<Duration>> {
+    let now = Instant::now();
+    if now >= self.deadline {
+      return Err(Error::new(TimedOut, "The metrics scrape took too long."));
+    }
+    Ok(Some(self.deadline - now))
+  }
+}
+
+impl Read for ScrapeStream {
+  fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+    try!(self.stream.set_read_timeout(try!(self.remaining())));
+    self.stream.read(buffer)
+  }
+}
+
+impl Write for ScrapeStream {
+  fn write(&mut self, bytes: &[u8]) -> Result<usize> {
+    try!(self.stream.set_write_timeout(try!(self.remaining())));
+    self.stream.write(bytes)
+  }
+
+  fn flush(&mut self) -> Result<()> {
+    self.stream.flush()
+  }
+}
+
+fn respond<S: Read + Write>(stream: &mut S, session: &Session) -> Result<()> {
+  let request = try!(read_request(stream));
+  let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+  let response = match (request_line.next(), request_line.next()) {
+    (Some("GET"), Some("/metrics")) => {
+      let body = render(session);
+      format!("HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", CONTENT_TYPE, body.len(), body)
+    },
+    _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+  };
+  stream.write_all(response.as_bytes())
+}
+
+// Only the request line matters, but the headers are read so that closing doesn't reset the connection
+fn read_request<S: Read>(stream: &mut S) -> Result<String> {
+  let mut request = Vec::new();
+  let mut buffer = [0u8; 1024];
+  while !request.ends_with(b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+    let bytes_read = try!(stream.read(&mut buffer));
+    if bytes_read == 0 {
+      break;
+    }
+    request.extend_from_slice(&buffer[..bytes_read]);
+  }
+  Ok(String::from_utf8_lossy(&request).into_owned())
+}
+
+impl AsRawFd for MetricsListener {
+  fn as_raw_fd(&self) -> RawFd {
+    self.listener.as_raw_fd()
+  }
+}
+
+impl Evented for MetricsListener {}
+
+#[test]
+fn stats_are_rendered_in_the_text_format() {
+  use frame::Frame;
+  use header::HeaderList;
+  use stats::StatsRecorder;
+  let mut recorder = StatsRecorder::new();
+  recorder.on_frame_sent(&Frame::send("/queue/\"quoted\"", "Animal".as_bytes()));
+  recorder.on_frame_sent(&Frame::nack("message-1"));
+  recorder.on_heartbeat_missed();
+  let message = Frame {
+    command: "MESSAGE".to_string(),
+    headers: HeaderList::new(),
+    body: "Vegetable".as_bytes().to_vec()
+  };
+  recorder.on_frame_received(&message, Some(("stomp-rs/0", "/topic/*")));
+  recorder.on_reconnect();
+  let mut output = String::new();
+  render_stats(&recorder.snapshot(), &mut output);
+  assert!(output.contains("# TYPE stomp_reconnects_total counter\nstomp_reconnects_total 1\n"));
+  assert!(output.contains("stomp_frames_sent_total{command=\"NACK\"} 1\n"));
+  assert!(output.contains("stomp_destination_frames_sent_total{destination=\"/queue/\\\"quoted\\\"\"} 1\n"));
+  assert!(output.contains("stomp_nacks_total 1\n"));
+  assert!(output.contains("stomp_heartbeats_missed_total 1\n"));
+  assert!(output.contains("stomp_receipt_latency_seconds_bucket{le=\"+Inf\"} 0\n"));
+  assert!(output.contains("stomp_subscription_messages_total{subscription=\"stomp-rs/0\",destination=\"/topic/*\"} 1\n"));
+  // Every sample belongs to a metric that was declared before it
+  let mut declared = Vec::new();
+  for line in output.lines() {
+    if line.starts_with("# TYPE ") {
+      declared.push(line.split_whitespace().nth(2).unwrap().to_string());
+    } else if !line.starts_with('#') {
+      let name = line.split(|c| c == '{' || c == ' ').next().unwrap();
+      assert!(declared.iter().any(|metric| name.starts_with(metric.as_str())), "undeclared sample: {}", line);
+    }
+  }
+}
+
+#[test]
+fn requests_are_read_up_to_the_blank_line() {
+  use std::io::Cursor;
+  let request = read_request(&mut Cursor::new(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec())).unwrap();
+  assert!(request.starts_with("GET /metrics "));
+  let truncated = read_request(&mut Cursor::new(b"GET /".to_vec())).unwrap();
+  assert_eq!("GET /", truncated);
+}
+
+#[test]
+fn slow_scrapes_are_cut_off_at_the_deadline() {
+  use std::thread;
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let address = listener.local_addr().unwrap();
+  // Each byte arrives well within a per-read timeout, but the request never ends
+  let client = thread::spawn(move || {
+    let mut stream = TcpStream::connect(address).unwrap();
+    for _ in 0..50 {
+      if stream.write_all(b"G").is_err() {
+        break;
+      }
+      thread::sleep(Duration::from_millis(20));
+    }
+  });
+  let (stream, _) = listener.accept().unwrap();
+  let started = Instant::now();
+  let mut scrape_stream = ScrapeStream::new(stream, Duration::from_millis(100)).unwrap();
+  assert!(read_request(&mut scrape_stream).is_err());
+  assert!(started.elapsed() < Duration::from_millis(500));
+  drop(scrape_stream);
+  client.join().unwrap();
+}
+
+#[test]
+fn one_scrape_is_answered_per_readable_event() {
+  use std::os::unix::net::UnixStream;
+  use session_builder::SessionBuilder;
+  let (client_end, mut broker_end) = UnixStream::pair().unwrap();
+  broker_end.write_all(b"CONNECTED\nversion:1.2\n\n\0").unwrap();
+  let session = SessionBuilder::new("localhost", 61613).start_with_transport(Box::new(client_end)).unwrap();
+  let metrics_listener = MetricsListener::bind("127.0.0.1:0").unwrap();
+  let address = metrics_listener.listener.local_addr().unwrap();
+  let mut scrapers: Vec<TcpStream> = (0..2).map(|_| {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+    stream.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    stream
+  }).collect();
+
+  metrics_listener.serve(&session);
+  let mut response = String::new();
+  scrapers[0].read_to_string(&mut response).unwrap();
+  assert!(response.starts_with("HTTP/1.1 200"));
+  let mut byte = [0u8; 1];
+  assert!(scrapers[1].read(&mut byte).is_err());
+
+  metrics_listener.serve(&session);
+  let mut response = String::new();
+  scrapers[1].read_to_string(&mut response).unwrap();
+  assert!(response.starts_with("HTTP/1.1 200"));
+}
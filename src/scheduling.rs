@@ -0,0 +1,105 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant, SystemTime};
+use frame::Frame;
+
+// Which broker-specific headers to use for features outside of the STOMP spec
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Broker {
+  Generic,
+  ActiveMq,
+  RabbitMq
+}
+
+#[derive(Clone, Copy)]
+pub struct Delay(pub Duration);
+#[derive(Clone, Copy)]
+pub struct DeliverAt(pub SystemTime);
+
+impl DeliverAt {
+  // Times in the past result in a delay of zero
+  pub fn delay(&self) -> Duration {
+    let DeliverAt(delivery_time) = *self;
+    match delivery_time.duration_since(SystemTime::now()) {
+      Ok(delay) => delay,
+      Err(_) => Duration::from_millis(0)
+    }
+  }
+}
+
+pub fn duration_to_millis(duration: Duration) -> u64 {
+  duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+// How long until `interval_ms` will have passed, given that `elapsed` already has; 0 if it has
+pub fn remaining_millis(interval_ms: u64, elapsed: Duration) -> u64 {
+  interval_ms.saturating_sub(duration_to_millis(elapsed))
+}
+
+// Frames held back by the client until they are due, for brokers that cannot delay delivery
+pub struct DelayedFrames {
+  frames: BTreeMap<(Instant, u64), Frame>,
+  // Keeps frames that are due at the same instant in the order they were sent
+  next_sequence_number: u64
+}
+
+impl DelayedFrames {
+  pub fn new() -> DelayedFrames {
+    DelayedFrames {
+      frames: BTreeMap::new(),
+      next_sequence_number: 0
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.frames.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.frames.is_empty()
+  }
+
+  pub fn push(&mut self, due: Instant, frame: Frame) {
+    self.frames.insert((due, self.next_sequence_number), frame);
+    self.next_sequence_number += 1;
+  }
+
+  pub fn next_due(&self) -> Option<Instant> {
+    self.frames.keys().next().map(|&(due, _)| due)
+  }
+
+  pub fn take_due(&mut self, now: Instant) -> Vec<Frame> {
+    let mut due_frames = Vec::new();
+    loop {
+      let key = match self.frames.keys().next() {
+        Some(&(due, sequence_number)) if due <= now => (due, sequence_number),
+        _ => break
+      };
+      if let Some(frame) = self.frames.remove(&key) {
+        due_frames.push(frame);
+      }
+    }
+    due_frames
+  }
+}
+
+#[test]
+fn delayed_frames_come_out_in_due_order() {
+  let now = Instant::now();
+  let mut delayed_frames = DelayedFrames::new();
+  delayed_frames.push(now + Duration::from_secs(60), Frame::send("/queue/a", "Mineral".as_bytes()));
+  delayed_frames.push(now, Frame::send("/queue/a", "Animal".as_bytes()));
+  delayed_frames.push(now, Frame::send("/queue/a", "Vegetable".as_bytes()));
+  assert_eq!(Some(now), delayed_frames.next_due());
+  let due_frames = delayed_frames.take_due(now + Duration::from_secs(1));
+  let bodies: Vec<&[u8]> = due_frames.iter().map(|frame| frame.body.as_ref()).collect();
+  assert_eq!(vec![&b"Animal"[..], &b"Vegetable"[..]], bodies);
+  assert_eq!(1, delayed_frames.len());
+  assert_eq!(Some(now + Duration::from_secs(60)), delayed_frames.next_due());
+}
+
+#[test]
+fn remaining_millis_counts_down_to_zero() {
+  assert_eq!(1500, remaining_millis(2000, Duration::from_millis(500)));
+  assert_eq!(0, remaining_millis(2000, Duration::from_millis(2000)));
+  assert_eq!(0, remaining_millis(2000, Duration::from_secs(5)));
+}
@@ -1,14 +1,17 @@
 use std::collections::hash_map::HashMap;
 use std::mem;
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use std::ops::DerefMut;
 use std::io::Read;
-use std::io::Write;
 use std::io::Result;
 use std::io::Error;
-use std::io::ErrorKind::{Other};
+use std::io::{self, Write};
+use std::io::ErrorKind::{Other, WouldBlock, UnexpectedEof, ConnectionAborted, InvalidInput};
+use std::net::{Shutdown, SocketAddr};
 use std::marker::PhantomData;
-use connection::Connection;
+use connection::{self, Connection, TimeoutKind};
+use transport::Transport;
 use subscription::AckMode;
 use subscription::AckMode::{Auto, Client, ClientIndividual};
 use subscription::AckOrNack;
@@ -16,18 +19,33 @@ use subscription::AckOrNack::{Ack, Nack};
 use subscription::{Subscription, MessageHandler, ToMessageHandler};
 use frame::Frame;
 use frame::ToFrameBody;
-use frame::Transmission::{HeartBeat, CompleteFrame, ConnectionClosed};
+use frame::Transmission::{HeartBeat, CompleteFrame, StreamingFrame, ConnectionClosed};
 use header;
 use header::HeaderList;
 use header::ReceiptId;
 use header::StompHeaderSet;
+use header::StompVersion;
 use transaction::Transaction;
+use batch::Batch;
 use session_builder::SessionBuilder;
 use message_builder::MessageBuilder;
 use subscription_builder::SubscriptionBuilder;
 use frame_buffer::FrameBuffer;
+use outbox::Outbox;
+use outbound_queue::OutboundQueue;
+use streaming::BodyReader;
+use scheduling::{Broker, DelayedFrames, duration_to_millis, remaining_millis};
+use broker_error::{BrokerError, BrokerErrorPolicy};
+use stats::{SessionStats, StatsRecorder};
+use compression;
+#[cfg(feature = "serde")]
+use codec::{Codec, Json};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+#[cfg(feature = "prometheus")]
+use prometheus::MetricsListener;
 
-use mio::{EventLoop, Handler, Token, ReadHint, Timeout};
+use mio::{EventLoop, Handler, Token, ReadHint, Timeout, Interest, PollOpt};
 
 pub trait FrameHandler {
   fn on_frame(&mut self, &Frame);
@@ -82,7 +100,8 @@ impl<'a, T> ReceiptHandler<'a, T> where T: 'a + ToFrameHandler<'a> {
 }
 
 const READ_BUFFER_SIZE: usize = 64 * 1024;
-const GRACE_PERIOD_MULTIPLIER: f64 = 2.0;
+#[cfg(feature = "prometheus")]
+const METRICS_TOKEN: Token = Token(1);
 
 pub struct Session <'a> {
   session_builder: SessionBuilder<'a>,
@@ -95,16 +114,44 @@ pub struct Session <'a> {
   rx_heartbeat_ms: u64,
   rx_heartbeat_timeout: Option<Timeout>,
   tx_heartbeat_ms: u64,
+  // Any bytes count as a heartbeat, so the timers only act once the connection has been idle
+  last_read: Instant,
+  last_write: Instant,
+  // The intervals agreed with the broker, before any scheduling adjustments
+  negotiated_heartbeat_ms: (u32, u32),
   pub subscriptions: HashMap<String, Subscription <'a>>,
   pub receipt_handlers: HashMap<String, Box<FrameHandler + 'a>>,
-  error_callback: Box<FrameHandler + 'a>,
+  // Messages forwarded to a dead-letter destination, keyed by the receipt id of the copy. They
+  // are acknowledged once it arrives; after a reconnect the broker redelivers them instead.
+  dead_letter_acks: HashMap<String, (Frame, AckMode)>,
+  pub outbox: Option<Outbox>,
+  outbound: OutboundQueue,
+  writable_interest: bool,
+  listening: bool,
+  // Queue and outbox lengths when the current batch was started
+  batch_start: Option<(usize, usize)>,
+  batched_frames: usize,
+  delayed_frames: DelayedFrames,
+  // The pending timeout for the earliest delayed frame and when it will fire
+  delayed_frames_timeout: Option<(Timeout, Instant)>,
+  error_callback: Box<FnMut(&BrokerError) + 'a>,
+  // Set by dispatch() when an ERROR frame arrives; the connection is torn down after dispatching
+  broker_error: Option<BrokerError>,
+  // Why listen() has to return an error once the event loop stops
+  listen_error: Option<Error>,
+  // Set by handle_receipt() when the outbox journal could not be updated
+  outbox_error: Option<Error>,
+  stats: StatsRecorder,
+  #[cfg(feature = "prometheus")]
+  metrics_listener: Option<MetricsListener>,
 	frame_send_callback: Box<FrameHandlerMut + 'a>,
 	frame_receive_callback: Box<FrameHandlerMut + 'a>
 }
 
 pub enum StompTimeout {
   SendHeartBeat,
-  ReceiveHeartBeat
+  ReceiveHeartBeat,
+  SendDelayedFrames
 }
 
 impl <'a> Handler for Session<'a> {
@@ -114,42 +161,120 @@ impl <'a> Handler for Session<'a> {
   fn timeout(&mut self, event_loop: &mut EventLoop<Session<'a>>, timeout: StompTimeout) {
     match timeout {
       StompTimeout::SendHeartBeat => self.send_heartbeat(event_loop),
-      StompTimeout::ReceiveHeartBeat => {
-        debug!("Did not receive a heartbeat in time.");
-      },
+      StompTimeout::ReceiveHeartBeat => self.check_rx_heartbeat(event_loop),
+      StompTimeout::SendDelayedFrames => {
+        self.delayed_frames_timeout = None;
+        self.send_due_frames(event_loop);
+      }
+    }
+    self.update_interest(event_loop);
+    self.schedule_delayed_frames(event_loop);
+  }
+
+  fn readable(&mut self, event_loop: &mut EventLoop<Session<'a>>, token: Token, _: ReadHint) {
+    #[cfg(feature = "prometheus")]
+    {
+      if token == METRICS_TOKEN {
+        if let Some(ref listener) = self.metrics_listener {
+          listener.serve(self);
+        }
+        return;
+      }
     }
+    let _ = token;
+    self.read_frames(event_loop);
+    // Handlers may have queued ACKs or other frames that couldn't be written yet
+    self.update_interest(event_loop);
+    // ...or delayed messages that need a timer
+    self.schedule_delayed_frames(event_loop);
+  }
+
+  fn writable(&mut self, event_loop: &mut EventLoop<Session<'a>>, _token: Token) {
+    debug!("Writable! {} bytes queued.", self.outbound.len());
+    if let Err(error) = self.flush_outbound() {
+      info!("Error while writing: {}", error);
+      self.reconnect(event_loop);
+    }
+    self.update_interest(event_loop);
+  }
+}
+
+fn write_error(error: Error) -> Error {
+  if connection::is_timeout(&error) {
+    TimeoutKind::Write.error()
+  } else {
+    error
+  }
+}
+
+impl <'a> Session <'a> {
+  fn read_frames(&mut self, event_loop: &mut EventLoop<Session<'a>>) {
+    // Streams that buffer decrypted bytes internally have to be drained until they block
+    while self.read_and_dispatch(event_loop) && self.connection.stream.buffers_reads() {}
   }
 
-  fn readable(&mut self, event_loop: &mut EventLoop<Session<'a>>, _token: Token, _: ReadHint) {
+  // Returns false once nothing more can be read from the current connection
+  fn read_and_dispatch(&mut self, event_loop: &mut EventLoop<Session<'a>>) -> bool {
     debug!("Readable! Buffer size: {}", &mut self.read_buffer.len());
     debug!("Frame buffer length: {}", &mut self.frame_buffer.len());
-    let bytes_read = match self.connection.tcp_stream.read(self.read_buffer.deref_mut()){
+    let bytes_read = match self.connection.stream.read(self.read_buffer.deref_mut()){
       Ok(0) => {
         info!("Read 0 bytes. Connection closed by remote host.");
         self.reconnect(event_loop);
-        return;
+        return false;
       },
       Ok(bytes_read) => bytes_read,
+      Err(ref error) if error.kind() == WouldBlock => {
+        debug!("Nothing to read after all.");
+        return false;
+      },
       Err(error) => {
         info!("Error while reading: {}", error);
         self.reconnect(event_loop);
-        return;
+        return false;
       },
     };
     info!("Read {} bytes", bytes_read);
+    self.last_read = Instant::now();
     self.frame_buffer.append(&self.read_buffer[..bytes_read]);
+    self.stats.on_frame_buffer_len(self.frame_buffer.len());
     let mut num_frames = 0u32;
     loop {
       debug!("Reading from frame buffer");
       match self.frame_buffer.read_transmission() {
-        Some(HeartBeat) => self.on_heartbeat(event_loop),
+        Some(HeartBeat) => {
+          debug!("Received HeartBeat");
+          self.stats.on_heartbeat_received();
+        },
         Some(CompleteFrame(mut frame)) => {
           debug!("Received frame!:\n{}", frame);
-          self.reset_rx_heartbeat_timeout(event_loop);
+          self.record_received(&frame);
 					self.frame_receive_callback.on_frame(&mut frame);
           self.dispatch(&mut frame);
           self.frame_buffer.recycle_frame(frame);
           num_frames += 1;
+          // A journal that could not be updated wins over reconnecting after an ERROR frame
+          if let Some(error) = self.outbox_error.take() {
+            self.listen_error = Some(error);
+            event_loop.shutdown();
+            return false;
+          }
+          if let Some(broker_error) = self.broker_error.take() {
+            self.tear_down(event_loop, broker_error);
+            return false;
+          }
+        },
+        Some(StreamingFrame(mut frame)) => {
+          debug!("Received the headers of a streamed frame:\n{}", frame);
+          self.record_received(&frame);
+					self.frame_receive_callback.on_frame(&mut frame);
+          if let Err(error) = self.dispatch_streamed(&mut frame) {
+            info!("Error while streaming a message body: {}", error);
+            self.reconnect(event_loop);
+            return false;
+          }
+          self.frame_buffer.recycle_frame(frame);
+          num_frames += 1;
         },
         Some(ConnectionClosed) => {
           info!("Connection closed by remote host.");
@@ -161,54 +286,144 @@ impl <'a> Handler for Session<'a> {
         }
       }
     }
+    true
   } 
-}
 
-impl <'a> Session <'a> {
   pub fn new(session_builder: SessionBuilder<'a>, connection: Connection, tx_heartbeat_ms: u32, rx_heartbeat_ms: u32) -> Session<'a> {
-    let modified_rx_heartbeat_ms : u32 = ((rx_heartbeat_ms as f64) * GRACE_PERIOD_MULTIPLIER) as u32;
+    let (tx_timer_ms, rx_timer_ms) = Connection::heartbeat_timers(
+      (tx_heartbeat_ms, rx_heartbeat_ms),
+      session_builder.heartbeat_send_fraction,
+      session_builder.heartbeat_grace
+    );
+    let outbound = OutboundQueue::new(session_builder.outbound_high_water_mark);
+    let mut frame_buffer = FrameBuffer::new();
+    frame_buffer.stream_bodies_over(session_builder.streaming_threshold);
+    frame_buffer.set_version(connection.version);
 
     Session {
       session_builder: session_builder,
       connection: connection,
-      frame_buffer: FrameBuffer::new(),
+      frame_buffer: frame_buffer,
       //TODO: Make this configurable
       read_buffer: Box::new([0; READ_BUFFER_SIZE]),
       next_transaction_id: 0,
       next_subscription_id: 0,
       next_receipt_id: 0,
-      rx_heartbeat_ms: modified_rx_heartbeat_ms as u64,
+      rx_heartbeat_ms: rx_timer_ms,
       rx_heartbeat_timeout: None,
-      tx_heartbeat_ms: (tx_heartbeat_ms as f64 / 2f64) as u64, //FIXME: Make this configurable, change units
+      tx_heartbeat_ms: tx_timer_ms,
+      last_read: Instant::now(),
+      last_write: Instant::now(),
+      negotiated_heartbeat_ms: (tx_heartbeat_ms, rx_heartbeat_ms),
       subscriptions: HashMap::new(),
       receipt_handlers: HashMap::new(),
-      error_callback: Box::new(Session::default_error_callback) as Box<FrameHandler>,
+      dead_letter_acks: HashMap::new(),
+      outbox: None,
+      outbound: outbound,
+      writable_interest: false,
+      listening: false,
+      batch_start: None,
+      batched_frames: 0,
+      delayed_frames: DelayedFrames::new(),
+      delayed_frames_timeout: None,
+      error_callback: Box::new(Session::default_error_callback),
+      broker_error: None,
+      listen_error: None,
+      outbox_error: None,
+      stats: StatsRecorder::new(),
+      #[cfg(feature = "prometheus")]
+      metrics_listener: None,
 			frame_send_callback: Box::new(Session::default_frame_send_callback) as Box<FrameHandlerMut>,
 			frame_receive_callback: Box::new(Session::default_frame_receive_callback) as Box<FrameHandlerMut>
     }
   }
 
+  fn record_received(&mut self, frame: &Frame) {
+    let subscriptions = &self.subscriptions;
+    let subscription = frame.headers.get_subscription()
+      .and_then(|header::Subscription(id)| subscriptions.get(id))
+      .map(|subscription| (subscription.id.as_ref(), subscription.destination.as_ref()));
+    self.stats.on_frame_received(frame, subscription);
+  }
+
+  // The broker closes the connection after an ERROR frame, so anything else it sent is ignored
+  fn tear_down(&mut self, event_loop: &mut EventLoop<Session<'a>>, broker_error: BrokerError) {
+    let _ = self.connection.stream.shutdown(Shutdown::Both);
+    match self.session_builder.broker_error_policy {
+      BrokerErrorPolicy::Reconnect => {
+        info!("Reconnecting after an ERROR frame.");
+        self.reconnect(event_loop);
+      },
+      BrokerErrorPolicy::EndListen => {
+        self.listen_error = Some(Error::new(ConnectionAborted, broker_error));
+        event_loop.shutdown();
+      }
+    }
+  }
+
   fn reconnect(&mut self, event_loop: &mut EventLoop<Session<'a>>) {
     let delay_between_attempts = 3_000u32; //TODO: Make this configurable
-    event_loop.deregister(&self.connection.tcp_stream).ok().expect("Failed to deregister dead tcp connection.");
+    event_loop.deregister(&self.connection.stream).ok().expect("Failed to deregister dead tcp connection.");
     self.clear_rx_heartbeat_timeout(event_loop);
     self.frame_buffer.reset();
+    let unwritten_frames = self.outbound.take_unwritten_frames();
+    let old_version = self.connection.version;
     loop {
-      match self.session_builder.clone().start() {
-        Ok(session) => {
-          info!("Reconnected successfully!");
-          let subscriptions = mem::replace(&mut self.subscriptions, HashMap::new());
+      // The open outbox is carried over instead, so frames that are not yet due stay held
+      let mut session_builder = self.session_builder.clone();
+      session_builder.outbox = None;
+      let attempt = if self.connection.reopen_transport {
+        match self.connection.stream.reopen() {
+          Ok(transport) => session_builder.start_with_transport(transport),
+          Err(error) => Err(error)
+        }
+      } else {
+        session_builder.start()
+      };
+      match attempt {
+        Ok(mut session) => {
+          info!("Reconnected successfully to {:?}, session id {:?}.", session.peer_address(), session.session_id());
+          // Everything that outlives a single connection is handed to the new session
+          mem::swap(&mut session.subscriptions, &mut self.subscriptions);
+          mem::swap(&mut session.receipt_handlers, &mut self.receipt_handlers);
+          mem::swap(&mut session.next_transaction_id, &mut self.next_transaction_id);
+          mem::swap(&mut session.next_subscription_id, &mut self.next_subscription_id);
+          mem::swap(&mut session.next_receipt_id, &mut self.next_receipt_id);
+          mem::swap(&mut session.delayed_frames, &mut self.delayed_frames);
+          mem::swap(&mut session.stats, &mut self.stats);
+          mem::swap(&mut session.outbox, &mut self.outbox);
+          mem::swap(&mut session.session_builder.outbox, &mut self.session_builder.outbox);
+          mem::swap(&mut session.error_callback, &mut self.error_callback);
+          mem::swap(&mut session.frame_send_callback, &mut self.frame_send_callback);
+          mem::swap(&mut session.frame_receive_callback, &mut self.frame_receive_callback);
+          #[cfg(feature = "prometheus")]
+          mem::swap(&mut session.metrics_listener, &mut self.metrics_listener);
           mem::replace(self, session);
-          self.subscriptions = subscriptions;
-          event_loop.register(&self.connection.tcp_stream, Token(0)).ok().expect("Couldn't register re-established connection with the event loop.");
-          self.register_rx_heartbeat_timeout(event_loop);
-          self.reset_rx_heartbeat_timeout(event_loop);
+          self.stats.on_reconnect();
+          event_loop.register(&self.connection.stream, Token(0)).ok().expect("Couldn't register re-established connection with the event loop.");
+          self.connection.stream.set_nonblocking(true).ok().expect("Couldn't make the re-established connection non-blocking.");
+          self.listening = true;
+          self.register_rx_heartbeat_timeout(event_loop, self.rx_heartbeat_ms);
+          if let Err(error) = self.resend_outbox() {
+            warn!("Could not resend the outbox: {}", error);
+          }
+          if let Err(error) = self.requeue_unwritten_frames(unwritten_frames, old_version) {
+            warn!("Could not resend the frames queued for the old connection: {}", error);
+          }
           info!("Resubscribing to {} destinations", self.subscriptions.len());
+          let version = self.version();
           let frames : Vec<Frame> = self.subscriptions
             .values()
             .map(|subscription| {
               info!("Re-subscribing to '{}'", &subscription.destination);
-              let mut subscribe_frame = Frame::subscribe(&subscription.id, &subscription.destination, subscription.ack_mode);
+              let ack_mode = match (subscription.ack_mode, version) {
+                (ClientIndividual, StompVersion::Stomp_v1_0) => {
+                  warn!("The broker now speaks STOMP 1.0, which has no client-individual ack mode; using 'client' for '{}'.", &subscription.destination);
+                  Client
+                },
+                (ack_mode, _) => ack_mode
+              };
+              let mut subscribe_frame = Frame::subscribe(&subscription.id, &subscription.destination, ack_mode);
               subscribe_frame.headers.concat(&mut subscription.headers.clone());
               subscribe_frame.headers.retain(|header| (*header).get_key() != "receipt"); //TODO: Find a way to clean this up.
               subscribe_frame
@@ -227,42 +442,105 @@ impl <'a> Session <'a> {
     }
   }
 
-  fn register_tx_heartbeat_timeout(&self, event_loop: &mut EventLoop<Session<'a>>) {
+  fn register_tx_heartbeat_timeout(&self, event_loop: &mut EventLoop<Session<'a>>, delay_ms: u64) {
     if self.tx_heartbeat_ms <= 0 {
       debug!("Heartbeat transmission ms is {}, no need to register a callback.", self.tx_heartbeat_ms);
       return;
     }
-    let _ = event_loop.timeout_ms(StompTimeout::SendHeartBeat, self.tx_heartbeat_ms);
+    let _ = event_loop.timeout_ms(StompTimeout::SendHeartBeat, delay_ms);
   }
 
-  fn register_rx_heartbeat_timeout(&mut self, event_loop: &mut EventLoop<Session<'a>>) {
+  fn register_rx_heartbeat_timeout(&mut self, event_loop: &mut EventLoop<Session<'a>>, delay_ms: u64) {
     if self.rx_heartbeat_ms <= 0 {
-      debug!("Heartbeat receipt ms is {}, no need to register a callback.", self.tx_heartbeat_ms);
+      debug!("Heartbeat receipt ms is {}, no need to register a callback.", self.rx_heartbeat_ms);
       return;
     }
     let timeout = event_loop
-      .timeout_ms(StompTimeout::ReceiveHeartBeat, self.rx_heartbeat_ms)
+      .timeout_ms(StompTimeout::ReceiveHeartBeat, delay_ms)
       .ok()
       .expect("Could not register a timeout to receive a heartbeat.");
     self.rx_heartbeat_timeout = Some(timeout);
   }
 
   fn send_heartbeat(&mut self, event_loop: &mut EventLoop<Session<'a>>) {
+    let remaining_ms = remaining_millis(self.tx_heartbeat_ms, self.last_write.elapsed());
+    // Frames written since the timer was set already told the broker we are alive
+    if remaining_ms > 0 {
+      debug!("Wrote to the connection recently, next heartbeat check in {}ms.", remaining_ms);
+      self.register_tx_heartbeat_timeout(event_loop, remaining_ms);
+      return;
+    }
     debug!("Sending heartbeat");
-    self.connection.tcp_stream.write("\n".as_bytes()).ok().expect("Could not send a heartbeat. Connection failed.");
-    let _ = self.connection.tcp_stream.flush();
-    self.register_tx_heartbeat_timeout(event_loop);
+    // Queued behind any frames that are still waiting to be written
+    self.outbound.push_bytes("\n".as_bytes());
+    self.stats.on_heartbeat_sent();
+    if let Err(error) = self.flush_outbound() {
+      info!("Could not send a heartbeat: {}", error);
+      self.reconnect(event_loop);
+    }
+    let tx_heartbeat_ms = self.tx_heartbeat_ms;
+    self.register_tx_heartbeat_timeout(event_loop, tx_heartbeat_ms);
   }
 
-  fn on_heartbeat(&mut self, event_loop: &mut EventLoop<Session<'a>>) {
-    debug!("Received HeartBeat");
-    self.reset_rx_heartbeat_timeout(event_loop);
+  fn check_rx_heartbeat(&mut self, event_loop: &mut EventLoop<Session<'a>>) {
+    self.rx_heartbeat_timeout = None;
+    let remaining_ms = remaining_millis(self.rx_heartbeat_ms, self.last_read.elapsed());
+    if remaining_ms > 0 {
+      self.register_rx_heartbeat_timeout(event_loop, remaining_ms);
+      return;
+    }
+    debug!("Did not receive a heartbeat in time.");
+    self.stats.on_heartbeat_missed();
+    // Keep watching, so that every silent interval is counted
+    self.register_rx_heartbeat_timeout(event_loop, self.rx_heartbeat_ms);
   }
 
-  fn reset_rx_heartbeat_timeout(&mut self, event_loop: &mut EventLoop<Session<'a>>) {
-    debug!("Resetting heartbeat rx timeout");
-    self.clear_rx_heartbeat_timeout(event_loop);
-    self.register_rx_heartbeat_timeout(event_loop);
+  fn schedule_delayed_frames(&mut self, event_loop: &mut EventLoop<Session<'a>>) {
+    let next_due = match self.delayed_frames.next_due() {
+      Some(next_due) => next_due,
+      None => return
+    };
+    if let Some((timeout, fires_at)) = self.delayed_frames_timeout {
+      if fires_at <= next_due {
+        return;
+      }
+      event_loop.clear_timeout(timeout);
+    }
+    let now = Instant::now();
+    let delay_ms = if next_due > now { duration_to_millis(next_due - now) } else { 0 };
+    match event_loop.timeout_ms(StompTimeout::SendDelayedFrames, delay_ms) {
+      Ok(timeout) => self.delayed_frames_timeout = Some((timeout, next_due)),
+      Err(error) => {
+        error!("Could not register a timeout for delayed messages: {:?}", error);
+        self.delayed_frames_timeout = None;
+      }
+    }
+  }
+
+  fn send_due_frames(&mut self, event_loop: &mut EventLoop<Session<'a>>) {
+    for frame in self.delayed_frames.take_due(Instant::now()) {
+      debug!("Sending delayed frame.");
+      if let Err(error) = self.send(frame) {
+        info!("Could not send a delayed message: {}", error);
+        self.reconnect(event_loop);
+        return;
+      }
+    }
+  }
+
+  fn update_interest(&mut self, event_loop: &mut EventLoop<Session<'a>>) {
+    let wants_writable = !self.outbound.is_empty() || self.connection.stream.has_pending_writes();
+    if wants_writable == self.writable_interest {
+      return;
+    }
+    let mut interest = Interest::readable();
+    if wants_writable {
+      interest.insert(Interest::writable());
+    }
+    match event_loop.reregister(&self.connection.stream, Token(0), interest, PollOpt::level()) {
+      Ok(_) => self.writable_interest = wants_writable,
+      Err(error) => error!("Could not update interest in the connection: {}", error)
+    }
   }
 
   fn clear_rx_heartbeat_timeout(&mut self, event_loop: &mut EventLoop<Session<'a>>) {
@@ -273,8 +551,8 @@ impl <'a> Session <'a> {
     });
   }
 
-  fn default_error_callback(frame : &Frame) {
-    error!("ERROR received:\n{}", frame);
+  fn default_error_callback(broker_error: &BrokerError) {
+    error!("{}", broker_error);
   }
 
 	fn default_frame_send_callback(frame : &mut Frame) {
@@ -285,9 +563,9 @@ impl <'a> Session <'a> {
 		debug!("FRAME received:\n{}", frame);
 	}
 
-  pub fn on_error<T: 'a>(&mut self, handler_convertible: T) where T : ToFrameHandler<'a> + 'a {
-    let handler = handler_convertible.to_frame_handler();
-    self.error_callback = handler;
+  // Called for ERROR frames, before the session reacts according to its BrokerErrorPolicy
+  pub fn on_error<F>(&mut self, callback: F) where F: FnMut(&BrokerError) + 'a {
+    self.error_callback = Box::new(callback);
   }
 
 	pub fn on_before_send<T: 'a>(&mut self, handler_convertible: T) where T : ToFrameHandlerMut<'a> + 'a {
@@ -303,19 +581,129 @@ impl <'a> Session <'a> {
   fn handle_receipt(&mut self, frame: &mut Frame) {
     match frame.headers.get_receipt_id() {
       Some(ReceiptId(ref receipt_id)) => {
-        let mut handler = match self.receipt_handlers.remove(*receipt_id) {
-          Some(handler) => {
+        self.stats.on_receipt(*receipt_id);
+        let confirmed = match self.outbox {
+          Some(ref mut outbox) => match outbox.confirm(*receipt_id) {
+            Ok(confirmed) => confirmed,
+            Err(error) => {
+              // The journal can no longer be trusted, so listen() ends with this error
+              error!("Could not remove '{}' from the outbox: {}", *receipt_id, error);
+              self.outbox_error = Some(error);
+              return;
+            }
+          },
+          None => false
+        };
+        if let Some((message, ack_mode)) = self.dead_letter_acks.remove(*receipt_id) {
+          debug!("Dead-letter copy '{}' was received, acknowledging the original.", *receipt_id);
+          return self.settle(&message, ack_mode, Ack, None);
+        }
+        match self.receipt_handlers.remove(*receipt_id) {
+          Some(mut handler) => {
             debug!("Calling handler for ReceiptId '{}'.", *receipt_id);
-            handler
+            handler.on_frame(&frame);
           },
+          None if confirmed => debug!("Outbox entry '{}' was confirmed.", *receipt_id),
           None => panic!("Received unexpected RECEIPT '{}'", *receipt_id)
         };
-        handler.on_frame(&frame);
       },
       None => panic!("Received RECEIPT frame without a receipt-id")
     };
   }
 
+  // The frame that caused an ERROR will never get its RECEIPT, so its handler is called with
+  // the ERROR frame and its outbox entry is removed. Returns whether there was such an entry.
+  fn refuse_receipt(&mut self, receipt_id: &str, frame: &Frame) -> bool {
+    self.stats.on_receipt_refused(receipt_id);
+    // A message whose dead-letter copy was refused stays unacknowledged and is redelivered
+    self.dead_letter_acks.remove(receipt_id);
+    if let Some(mut handler) = self.receipt_handlers.remove(receipt_id) {
+      debug!("Calling handler for ReceiptId '{}' with the ERROR frame.", receipt_id);
+      handler.on_frame(frame);
+    }
+    match self.outbox {
+      Some(ref mut outbox) => match outbox.reject(receipt_id) {
+        Ok(rejected) => rejected,
+        Err(error) => {
+          error!("Could not remove '{}' from the outbox: {}", receipt_id, error);
+          self.outbox_error = Some(error);
+          false
+        }
+      },
+      None => false
+    }
+  }
+
+  pub fn resend_outbox(&mut self) -> Result<()> {
+    let outbox = match self.outbox {
+      Some(ref outbox) => outbox,
+      None => return Ok(())
+    };
+    let pending = outbox.pending();
+    if pending.is_empty() {
+      return Ok(());
+    }
+    info!("Resending {} unconfirmed message(s) from the outbox.", pending.len());
+    let version = self.connection.version;
+    for frame in pending {
+      let mut frame = frame.clone();
+      frame.headers.escape_for(version);
+      self.outbound.push_frame(&frame);
+      self.stats.on_frame_queued(&frame);
+    }
+    self.flush_outbound()
+  }
+
+  // Queues the SEND frames that never made it onto the old connection again, unless the outbox
+  // has resent them already. Acknowledgements and transactions belonged to the old connection,
+  // and subscriptions are renewed separately.
+  fn requeue_unwritten_frames(&mut self, unwritten_frames: Vec<Vec<u8>>, old_version: StompVersion) -> Result<()> {
+    let mut discarded = 0;
+    for encoded_frame in unwritten_frames {
+      let mut frame_buffer = FrameBuffer::new();
+      frame_buffer.set_version(old_version);
+      frame_buffer.append(&encoded_frame);
+      let frame = match frame_buffer.read_transmission() {
+        Some(CompleteFrame(frame)) => frame,
+        _ => {
+          discarded += 1;
+          continue;
+        }
+      };
+      let resent_by_outbox = match (self.outbox.as_ref(), frame.headers.get_receipt()) {
+        (Some(outbox), Some(header::Receipt(receipt_id))) => outbox.contains(receipt_id),
+        _ => false
+      };
+      if resent_by_outbox {
+        continue;
+      }
+      // Headers were escaped for the old version, so they are only reused as they are
+      if frame.command == "SEND" && frame.headers.get_transaction().is_none() && self.connection.version == old_version {
+        self.outbound.push_encoded_frame(&encoded_frame);
+        self.stats.on_frame_queued(&frame);
+      } else {
+        discarded += 1;
+      }
+    }
+    if discarded > 0 {
+      warn!("Discarded {} frame(s) that were never written to the old connection.", discarded);
+    }
+    self.flush_outbound()
+  }
+
+  // Holds back the delayed frames replayed from the outbox until they are due
+  pub fn hold_outbox_frames(&mut self) {
+    let held: Vec<(SystemTime, Frame)> = match self.outbox {
+      Some(ref outbox) => outbox.held().into_iter().map(|(due, frame)| (due, frame.clone())).collect(),
+      None => return
+    };
+    let (now, wall_clock_now) = (Instant::now(), SystemTime::now());
+    for (due, frame) in held {
+      let delay = due.duration_since(wall_clock_now).unwrap_or(Duration::from_millis(0));
+      self.delayed_frames.push(now + delay, frame);
+    }
+  }
+
   pub fn outstanding_receipts(&self) -> Vec<&str> {
     self.receipt_handlers.keys().map(|key| key.as_ref()).collect()
   }
@@ -339,10 +727,40 @@ impl <'a> Session <'a> {
   }
 
   pub fn message<'b, T: ToFrameBody> (&'b mut self, destination: &str, body_convertible: T) -> MessageBuilder<'b, 'a> {
-    let send_frame = Frame::send(destination, body_convertible.to_frame_body());
+    let send_frame = Frame::send_owned(destination, body_convertible.into_frame_body());
+    MessageBuilder {
+     session: self,
+     frame: send_frame,
+     body_source: None,
+     delay: None
+    }
+  }
+
+  #[cfg(feature = "serde")]
+  pub fn message_json<'b, T: Serialize> (&'b mut self, destination: &str, value: &T) -> Result<MessageBuilder<'b, 'a>> {
+    self.message_with(destination, Json, value)
+  }
+
+  #[cfg(feature = "serde")]
+  pub fn message_with<'b, C: Codec, T: Serialize> (&'b mut self, destination: &str, codec: C, value: &T) -> Result<MessageBuilder<'b, 'a>> {
+    let body = try!(codec.encode(value));
+    let mut send_frame = Frame::send_owned(destination, body);
+    send_frame.headers.push(header::Header::new("content-type", codec.content_type()));
+    Ok(MessageBuilder {
+     session: self,
+     frame: send_frame,
+     body_source: None,
+     delay: None
+    })
+  }
+
+  pub fn message_stream<'b, R: Read + 'b> (&'b mut self, destination: &str, body: R, content_length: u64) -> MessageBuilder<'b, 'a> {
+    let send_frame = Frame::send_streamed(destination, content_length);
     MessageBuilder {
      session: self,
-     frame: send_frame
+     frame: send_frame,
+     body_source: Some((Box::new(body) as Box<Read + 'b>, content_length)),
+     delay: None
     }
   }
 
@@ -354,6 +772,8 @@ impl <'a> Session <'a> {
       ack_mode: AckMode::Auto,
       handler: message_handler,
       headers: HeaderList::new(),
+      deduplication: None,
+      dead_letter: None
     }
   }
 
@@ -374,45 +794,337 @@ impl <'a> Session <'a> {
     Ok(transaction)
   }
 
+  pub fn batch<'b>(&'b mut self) -> Batch<'b, 'a> {
+    Batch::new(self)
+  }
+
+  pub fn start_batch(&mut self) {
+    if self.batch_start.is_none() {
+      let outbox_len = self.outbox.as_ref().map(|outbox| outbox.len()).unwrap_or(0);
+      self.batch_start = Some((self.outbound.len(), outbox_len));
+      self.batched_frames = 0;
+    }
+  }
+
+  pub fn batched_frames(&self) -> usize {
+    self.batched_frames
+  }
+
+  pub fn flush_batch(&mut self) -> Result<()> {
+    if self.batch_start.take().is_none() {
+      return Ok(());
+    }
+    debug!("Sending a batch of {} frames ({} bytes queued).", self.batched_frames, self.outbound.len());
+    self.batched_frames = 0;
+    self.flush_outbound()
+  }
+
+  pub fn discard_batch(&mut self) {
+    if let Some((queue_len, outbox_len)) = self.batch_start.take() {
+      if self.batched_frames > 0 {
+        warn!("Discarding a batch of {} frames that was never sent.", self.batched_frames);
+      }
+      self.outbound.truncate(queue_len);
+      self.stats.on_frames_discarded(self.outbound.frames_len());
+      if let Some(ref mut outbox) = self.outbox {
+        if let Err(error) = outbox.truncate(outbox_len) {
+          error!("Could not remove discarded messages from the outbox: {}", error);
+        }
+      }
+    }
+    self.batched_frames = 0;
+  }
+
+  pub fn broker(&self) -> Broker {
+    self.session_builder.broker
+  }
+
+  // Holds the frame back until `delay` has passed. Delayed frames are sent by the event loop,
+  // so they only go out while the session is listening. With an outbox they are journaled
+  // right away, so they are not lost if the process dies before they are due.
+  pub fn send_later(&mut self, mut frame: Frame, delay: Duration) -> Result<()> {
+    if delay == Duration::from_millis(0) {
+      return self.send(frame);
+    }
+    // The frame would go out after the transaction had been committed or aborted
+    if frame.headers.get_transaction().is_some() {
+      return Err(Error::new(InvalidInput, "Frames cannot be delayed by the client inside a transaction."));
+    }
+    if frame.command == "SEND" {
+      if let Some(ref mut outbox) = self.outbox {
+        try!(outbox.hold(&mut frame, SystemTime::now() + delay));
+      }
+    }
+    debug!("Delaying frame by {}ms.", duration_to_millis(delay));
+    self.delayed_frames.push(Instant::now() + delay, frame);
+    Ok(())
+  }
+
+  pub fn delayed_len(&self) -> usize {
+    self.delayed_frames.len()
+  }
+
+  // The version of the protocol the broker chose
+  pub fn version(&self) -> StompVersion {
+    self.connection.version
+  }
+
+  // The broker's 'server' header, e.g. "ActiveMQ/5.15.0"
+  pub fn server(&self) -> Option<&str> {
+    self.connection.server.as_ref().map(|server| server.as_ref())
+  }
+
+  // The broker's id for this session, which changes on every reconnect
+  pub fn session_id(&self) -> Option<&str> {
+    self.connection.session_id.as_ref().map(|session_id| session_id.as_ref())
+  }
+
+  // Milliseconds between heartbeats that (we send, the broker sends); 0 means none
+  pub fn heartbeat(&self) -> (u32, u32) {
+    self.negotiated_heartbeat_ms
+  }
+
+  // Where the current connection's host name resolved to. Reconnecting resolves the name
+  // again, so this follows DNS changes. None for Unix domain sockets and custom transports.
+  pub fn peer_address(&self) -> Option<SocketAddr> {
+    self.connection.peer_address
+  }
+
+  #[cfg(feature = "prometheus")]
+  fn register_metrics_listener(&mut self, event_loop: &mut EventLoop<Session<'a>>) -> Result<()> {
+    if self.metrics_listener.is_none() {
+      if let Some(ref address) = self.session_builder.metrics_endpoint {
+        self.metrics_listener = Some(try!(MetricsListener::bind(address)));
+      }
+    }
+    if let Some(ref listener) = self.metrics_listener {
+      try!(event_loop.register_opt(listener, METRICS_TOKEN, Interest::readable(), PollOpt::level()));
+    }
+    Ok(())
+  }
+
+  pub fn is_listening(&self) -> bool {
+    self.listening
+  }
+
+  // Counters since the session was created; they carry over when it reconnects
+  pub fn stats(&self) -> SessionStats {
+    self.stats.snapshot()
+  }
+
+  pub fn outbound_len(&self) -> usize {
+    self.outbound.len()
+  }
+
+  pub fn outbound_high_water_mark(&self) -> usize {
+    self.outbound.high_water_mark()
+  }
+
+  // Outside of listen() the connection blocks, so this writes everything that is queued.
+  // While listening it writes what it can and leaves the rest to the event loop.
+  pub fn flush_outbound(&mut self) -> Result<()> {
+    let queued = self.outbound.len();
+    let result = self.outbound.write_to(&mut self.connection.stream);
+    if self.outbound.len() < queued {
+      self.last_write = Instant::now();
+    }
+    self.stats.on_frames_written(self.outbound.take_written_frames());
+    match result {
+      // A blocking write only gives up once the write timeout has passed
+      Ok(false) if !self.listening => Err(TimeoutKind::Write.error()),
+      Ok(_) => Ok(()),//FIXME: Replace 'Other' below with a more meaningful ErrorKind
+      Err(_) => Err(Error::new(Other, "Could not send frame: the connection to the server was lost."))
+    }
+  }
+
   pub fn send(&mut self, frame: Frame) -> Result<()> {
 		let mut mut_frame = frame;
 		self.frame_send_callback.on_frame(&mut mut_frame);
-    match mut_frame.write(&mut self.connection.tcp_stream) {
-      Ok(_) => Ok(()),//FIXME: Replace 'Other' below with a more meaningful ErrorKind
-      Err(_) => Err(Error::new(Other, "Could not send frame: the connection to the server was lost."))
+    if let Some(compression) = self.session_builder.compression {
+      try!(compression.compress_frame(&mut mut_frame));
+    }
+    // Replaying a transactional SEND after a reconnect would name a transaction the new
+    // connection never began, so those are left to the transaction to retry.
+    // The outbox is given the frame before it is escaped, since a reconnect may negotiate
+    // a different version.
+    if mut_frame.command == "SEND" && mut_frame.headers.get_transaction().is_none() {
+      if let Some(ref mut outbox) = self.outbox {
+        try!(outbox.record(&mut mut_frame));
+      }
+    }
+    mut_frame.headers.escape_for(self.connection.version);
+    debug!("Queueing frame:\n{}", mut_frame);
+    self.outbound.push_frame(&mut_frame);
+    self.stats.on_frame_queued(&mut_frame);
+    if self.batch_start.is_some() {
+      self.batched_frames += 1;
+      return Ok(());
     }
+    self.flush_outbound()
+  }
+
+  // The body is copied from `body` straight to the connection instead of being buffered
+  // The connection blocks until the whole body is written, so while listening, heartbeats,
+  // delayed frames and incoming frames wait for it as well.
+  pub fn send_streamed(&mut self, frame: Frame, body: &mut Read, content_length: u64) -> Result<()> {
+    // Writing straight to the connection would flush the batch early
+    if self.batch_start.is_some() {
+      return Err(Error::new(InvalidInput, "Streamed messages cannot be sent inside a batch."));
+    }
+    if frame.command == "SEND" && self.outbox.is_some() {
+      return Err(Error::new(InvalidInput, "Streamed message bodies cannot be recorded in the outbox."));
+    }
+		let mut mut_frame = frame;
+		self.frame_send_callback.on_frame(&mut mut_frame);
+    mut_frame.headers.escape_for(self.connection.version);
+    // Anything queued has to go out first; while listening this means waiting for it
+    try!(self.connection.stream.set_nonblocking(false));
+    let result = self.write_streamed(&mut_frame, body, content_length);
+    if self.listening {
+      try!(self.connection.stream.set_nonblocking(true));
+    }
+    result
+  }
+
+  fn write_streamed(&mut self, frame: &Frame, body: &mut Read, content_length: u64) -> Result<()> {
+    try!(self.flush_outbound());
+    if !self.outbound.is_empty() {
+      return Err(TimeoutKind::Write.error());
+    }
+    debug!("Streaming frame:\n{}", frame);
+    let stream = &mut self.connection.stream;
+    try!(frame.write_headers(stream).map_err(write_error));
+    let bytes_copied = try!(io::copy(&mut body.take(content_length), stream).map_err(write_error));
+    if bytes_copied != content_length {
+      // The frame on the wire is now corrupt, so the connection cannot be used anymore
+      let _ = stream.shutdown(Shutdown::Both);
+      return Err(Error::new(UnexpectedEof, "Message body was shorter than its content-length."));
+    }
+    try!(stream.write_all(&[0]).map_err(write_error));
+    try!(stream.flush().map_err(write_error));
+    self.last_write = Instant::now();
+    self.stats.on_frame_sent_with_body(frame, content_length as usize);
+    Ok(())
   }
 
   pub fn dispatch(&mut self, frame: &mut Frame) {
     // Check for ERROR frame
     match frame.command.as_ref() {
-       "ERROR" => return self.error_callback.on_frame(&frame),
+       "ERROR" => {
+         let mut broker_error = BrokerError::from_frame(frame);
+         if let Some(receipt_id) = broker_error.receipt_id.clone() {
+           broker_error.removed_from_outbox = self.refuse_receipt(&receipt_id, frame);
+         }
+         (self.error_callback)(&broker_error);
+         self.broker_error = Some(broker_error);
+         return;
+       },
        "RECEIPT" => return self.handle_receipt(frame),
         _ => {} // No operation
     };
- 
-    let ack_mode : AckMode;
-    let callback_result : AckOrNack; 
-    { // This extra scope is required to free up `frame` and `self.subscriptions`
-      // following a borrow.
-
-      // Find the subscription ID on the frame that was received
-      let header::Subscription(sub_id) = 
-        frame.headers
-        .get_subscription()
-        .expect("Frame did not contain a subscription header.");
-
-      // Look up the appropriate Subscription object
-      let subscription = 
-         self.subscriptions
-         .get_mut(sub_id)
-         .expect("Received a message for an unknown subscription.");
-
-      // Take note of the ack_mode used by this Subscription
-      ack_mode = subscription.ack_mode;
-      // Invoke the callback in the Subscription, providing the frame
-      // Take note of whether this frame should be ACKed or NACKed
-      callback_result = (*subscription.handler).on_message(&frame);
+
+    let max_decoded_size = self.session_builder.compression
+      .map_or(compression::DEFAULT_MAX_DECODED_SIZE, |compression| compression.max_decoded_size);
+    if let Err(error) = compression::decompress_frame(frame, max_decoded_size) {
+      error!("Delivering message with its body still encoded: {}", error);
+    }
+    let (ack_mode, callback_result, dead_letter_frame) = Session::deliver(&mut self.subscriptions, frame, None);
+    self.settle(frame, ack_mode, callback_result, dead_letter_frame);
+  } 
+
+  fn dispatch_streamed(&mut self, frame: &mut Frame) -> Result<()> {
+    // The rest of the body is read straight off the connection, so reads have to block
+    try!(self.connection.stream.set_nonblocking(false));
+    let (ack_mode, callback_result, dead_letter_frame) = {
+      let mut body = BodyReader::new(&mut self.frame_buffer, &mut self.connection.stream);
+      let outcome = Session::deliver(&mut self.subscriptions, frame, Some(&mut body as &mut Read));
+      try!(body.finish());
+      outcome
+    };
+    if self.listening {
+      try!(self.connection.stream.set_nonblocking(true));
+    }
+    self.settle(frame, ack_mode, callback_result, dead_letter_frame);
+    Ok(())
+  }
+
+  // Hands a MESSAGE to its subscription and works out how it should be acknowledged
+  fn deliver(subscriptions: &mut HashMap<String, Subscription<'a>>, frame: &mut Frame, body: Option<&mut Read>) -> (AckMode, AckOrNack, Option<Frame>) {
+    // Find the subscription ID on the frame that was received
+    let sub_id = 
+      frame.headers
+      .get_subscription()
+      .expect("Frame did not contain a subscription header.")
+      .0
+      .to_string();
+
+    // Look up the appropriate Subscription object
+    let subscription = 
+       subscriptions
+       .get_mut(&sub_id)
+       .expect("Received a message for an unknown subscription.");
+
+    // Take note of the ack_mode used by this Subscription
+    let ack_mode = subscription.ack_mode;
+    let is_duplicate = match subscription.duplicate_filter {
+      Some(ref mut filter) => filter.is_duplicate(&frame),
+      None => false
+    };
+    if is_duplicate {
+      // Redeliveries of messages we've already handled are acknowledged without
+      // bothering the handler
+      debug!("Discarding duplicate message for subscription '{}'.", sub_id);
+      return (ack_mode, Ack, None);
+    }
+
+    // Invoke the callback in the Subscription, providing the frame
+    // Take note of whether this frame should be ACKed or NACKed
+    let streamed = body.is_some();
+    let callback_result = match body {
+      Some(body) => (*subscription.handler).on_message_stream(frame, body),
+      None => (*subscription.handler).on_message(&frame)
+    };
+    if let (Ack, Some(ref mut filter)) = (callback_result, subscription.duplicate_filter.as_mut()) {
+      filter.remember(&frame);
+    }
+    let mut dead_letter_frame = None;
+    if let Some(ref mut tracker) = subscription.redelivery_tracker {
+      match callback_result {
+        Ack => tracker.forget(&frame),
+        Nack => {
+          let deliveries = tracker.record_delivery(&frame);
+          if tracker.is_exhausted(deliveries) && streamed {
+            // The body went to the handler without being kept, so there is nothing to forward
+            warn!("Streamed message was rejected {} times but cannot be forwarded to '{}', NACKing it.", deliveries, tracker.destination());
+          } else if tracker.is_exhausted(deliveries) {
+            warn!("Message was rejected {} times, forwarding it to '{}'.", deliveries, tracker.destination());
+            dead_letter_frame = Some(tracker.dead_letter_frame(&frame, deliveries));
+          }
+        }
+      }
+    }
+    (ack_mode, callback_result, dead_letter_frame)
+  }
+
+  fn settle(&mut self, frame: &Frame, ack_mode: AckMode, callback_result: AckOrNack, dead_letter_frame: Option<Frame>) {
+    // A poison message is only acknowledged once the broker has confirmed it has a copy of it
+    // elsewhere, see handle_receipt()
+    if let Some(mut dead_letter_frame) = dead_letter_frame {
+      let receipt_id = format!("dead-letter/{}", self.generate_receipt_id());
+      dead_letter_frame.headers.push(header::Header::new("receipt", receipt_id.as_ref()));
+      match self.send(dead_letter_frame) {
+        Ok(_) => {
+          // Only the headers are needed to acknowledge it
+          let message = Frame {
+            command: frame.command.clone(),
+            headers: frame.headers.clone(),
+            body: Vec::new()
+          };
+          self.dead_letter_acks.insert(receipt_id, (message, ack_mode));
+          return;
+        },
+        Err(error) => error!("Could not forward message to the dead-letter destination: {}", error)
+      }
     }
 
     debug!("Executing.");
@@ -421,33 +1133,383 @@ impl <'a> Session <'a> {
         debug!("Auto ack, no frame sent.");
       }
       Client | ClientIndividual => {
-        let header::Ack(ack_id) = 
-          frame.headers
-          .get_ack()
-          .expect("Message did not have an 'ack' header.");
-        match callback_result {
-          Ack =>  self.acknowledge_frame(ack_id),
-          Nack => self.negatively_acknowledge_frame(ack_id)
-        }.unwrap_or_else(|error|panic!(format!("Could not acknowledge frame: {}", error)));
+        let version = self.connection.version;
+        let command = match (callback_result, version) {
+          (Ack, _) => "ACK",
+          (Nack, StompVersion::Stomp_v1_0) => {
+            // The broker will redeliver the message once the session ends
+            warn!("STOMP 1.0 has no NACK, leaving the message unacknowledged.");
+            return;
+          },
+          (Nack, _) => "NACK"
+        };
+        let settle_frame = Frame::settle_message(command, frame, version)
+          .expect("Message did not have the headers needed to acknowledge it.");
+        self.send(settle_frame)
+          .unwrap_or_else(|error|panic!(format!("Could not acknowledge frame: {}", error)));
+        let subscriptions = &self.subscriptions;
+        if let Some(subscription) = frame.headers.get_subscription().and_then(|header::Subscription(id)| subscriptions.get(id)) {
+          self.stats.on_settled((&subscription.id, &subscription.destination), command);
+        }
       } // Client | ...
     }
-  } 
+  }
 
-  fn acknowledge_frame(&mut self, ack_id: &str) -> Result<()> {
-    let ack_frame = Frame::ack(ack_id);
-    self.send(ack_frame)
+  pub fn listen(&mut self) -> Result<()> {
+    let mut event_loop : EventLoop<Session<'a>> = EventLoop::new().unwrap();
+    let _ = event_loop.register(&self.connection.stream, Token(0));
+    #[cfg(feature = "prometheus")]
+    try!(self.register_metrics_listener(&mut event_loop));
+    // Writes are driven by writable events from here on, so they must never block
+    try!(self.connection.stream.set_nonblocking(true));
+    self.listening = true;
+    self.writable_interest = false;
+    // Whatever arrived before listening was not being watched for
+    self.last_read = Instant::now();
+    let (tx_heartbeat_ms, rx_heartbeat_ms) = (self.tx_heartbeat_ms, self.rx_heartbeat_ms);
+    self.register_tx_heartbeat_timeout(&mut event_loop, tx_heartbeat_ms);
+    self.register_rx_heartbeat_timeout(&mut event_loop, rx_heartbeat_ms);
+    self.update_interest(&mut event_loop);
+    self.delayed_frames_timeout = None;
+    self.schedule_delayed_frames(&mut event_loop);
+    let result = event_loop.run(self);
+    self.listening = false;
+    if !self.delayed_frames.is_empty() {
+      warn!("{} delayed message(s) were not sent before the session stopped listening.", self.delayed_frames.len());
+    }
+    if let Some(error) = self.listen_error.take() {
+      if !self.outbound.is_empty() {
+        warn!("Discarding {} queued bytes that were never written to the closed connection.", self.outbound.len());
+        self.outbound.clear();
+        self.stats.on_frames_discarded(0);
+      }
+      return Err(error);
+    }
+    try!(self.connection.stream.set_nonblocking(false));
+    try!(self.flush_outbound());
+    result
   }
+}
+
+// A session whose broker is the returned end of a socket pair. The CONNECTED frame is
+// written up front, so no broker thread is needed.
+#[cfg(test)]
+fn session_over_socket_pair<'a>(builder: SessionBuilder<'a>, version: &str) -> (Session<'a>, ::std::os::unix::net::UnixStream) {
+  let (client_end, mut broker_end) = ::std::os::unix::net::UnixStream::pair().unwrap();
+  broker_end.write_all(format!("CONNECTED\nversion:{}\n\n\0", version).as_bytes()).unwrap();
+  let session = builder.start_with_transport(Box::new(client_end)).unwrap();
+  assert!(read_frame(&mut broker_end).starts_with("CONNECT\n"));
+  (session, broker_end)
+}
 
-  fn negatively_acknowledge_frame(&mut self, ack_id: &str) -> Result<()>{
-    let nack_frame = Frame::nack(ack_id);
-    self.send(nack_frame)
+// Reads up to and excluding the next null octet
+#[cfg(test)]
+fn read_frame<R: Read>(stream: &mut R) -> String {
+  let mut frame = Vec::new();
+  let mut byte = [0u8; 1];
+  while stream.read(&mut byte).unwrap() == 1 && byte[0] != 0 {
+    frame.push(byte[0]);
   }
+  String::from_utf8(frame).unwrap()
+}
 
-  pub fn listen(&mut self) -> Result<()> {
-    let mut event_loop : EventLoop<Session<'a>> = EventLoop::new().unwrap();
-    let _ = event_loop.register(&self.connection.tcp_stream, Token(0));
-    self.register_tx_heartbeat_timeout(&mut event_loop);
-    self.register_rx_heartbeat_timeout(&mut event_loop);
-    event_loop.run(self)
+#[test]
+fn transactional_sends_are_not_journaled() {
+  use outbox::PersistentOutbox;
+  let path = ::std::env::temp_dir().join(format!("stomp-rs-transactional-{}.journal", ::std::process::id()));
+  let _ = ::std::fs::remove_file(&path);
+  let builder = SessionBuilder::new("localhost", 61613).with(PersistentOutbox(path.to_str().unwrap()));
+  let (mut session, mut broker_end) = session_over_socket_pair(builder, "1.2");
+  {
+    let mut transaction = session.begin_transaction().unwrap();
+    transaction.message("/queue/a", "Animal").send().unwrap();
+    transaction.commit().unwrap();
   }
+  assert_eq!(0, session.outbox.as_ref().unwrap().len());
+  session.message("/queue/a", "Vegetable").send().unwrap();
+  assert_eq!(1, session.outbox.as_ref().unwrap().len());
+  assert!(read_frame(&mut broker_end).starts_with("BEGIN\n"));
+  let _ = ::std::fs::remove_file(&path);
+}
+
+#[test]
+fn delayed_sends_are_journaled_and_refused_in_transactions() {
+  use outbox::PersistentOutbox;
+  let path = ::std::env::temp_dir().join(format!("stomp-rs-delayed-{}.journal", ::std::process::id()));
+  let _ = ::std::fs::remove_file(&path);
+  let builder = SessionBuilder::new("localhost", 61613).with(PersistentOutbox(path.to_str().unwrap()));
+  let (mut session, mut broker_end) = session_over_socket_pair(builder, "1.2");
+  let mut transactional = Frame::send("/queue/a", "Animal".as_bytes());
+  transactional.headers.push(header::Header::new("transaction", "tx/0"));
+  assert_eq!(InvalidInput, session.send_later(transactional, Duration::from_millis(10)).unwrap_err().kind());
+
+  session.send_later(Frame::send("/queue/a", "Vegetable".as_bytes()), Duration::from_millis(10)).unwrap();
+  assert_eq!(1, session.outbox.as_ref().unwrap().len());
+  assert_eq!(0, session.outbox.as_ref().unwrap().pending().len());
+  thread::sleep(Duration::from_millis(20));
+  let mut event_loop = EventLoop::new().unwrap();
+  session.send_due_frames(&mut event_loop);
+  let sent = read_frame(&mut broker_end);
+  assert!(sent.starts_with("SEND\n"));
+  assert!(sent.contains("Vegetable"));
+  assert_eq!(1, session.outbox.as_ref().unwrap().len());
+  assert_eq!(1, session.outbox.as_ref().unwrap().pending().len());
+  let _ = ::std::fs::remove_file(&path);
+}
+
+#[test]
+fn delayed_sends_are_held_until_due_after_a_restart() {
+  use outbox::PersistentOutbox;
+  let path = ::std::env::temp_dir().join(format!("stomp-rs-restart-{}.journal", ::std::process::id()));
+  let _ = ::std::fs::remove_file(&path);
+  {
+    let builder = SessionBuilder::new("localhost", 61613).with(PersistentOutbox(path.to_str().unwrap()));
+    let (mut session, _) = session_over_socket_pair(builder, "1.2");
+    session.send_later(Frame::send("/queue/a", "Vegetable".as_bytes()), Duration::from_secs(60)).unwrap();
+  }
+  let builder = SessionBuilder::new("localhost", 61613).with(PersistentOutbox(path.to_str().unwrap()));
+  let (session, _) = session_over_socket_pair(builder, "1.2");
+  assert_eq!(1, session.delayed_len());
+  assert_eq!(0, session.outbox.as_ref().unwrap().pending().len());
+  let _ = ::std::fs::remove_file(&path);
+}
+
+#[test]
+fn stomp_1_0_sessions_escape_replays_and_refuse_client_individual_acks() {
+  use outbox::{Outbox, PersistentOutbox};
+  let path = ::std::env::temp_dir().join(format!("stomp-rs-stomp-1-0-{}.journal", ::std::process::id()));
+  let _ = ::std::fs::remove_file(&path);
+  {
+    let mut outbox = Outbox::open(&path).unwrap();
+    let mut frame = Frame::send("/queue/a", "Animal".as_bytes());
+    frame.headers.push(header::Header::new("note", "a:b"));
+    outbox.record(&mut frame).unwrap();
+  }
+  let builder = SessionBuilder::new("localhost", 61613).with(PersistentOutbox(path.to_str().unwrap()));
+  let (mut session, mut broker_end) = session_over_socket_pair(builder, "1.0");
+  // STOMP 1.0 has no escape sequences
+  assert!(read_frame(&mut broker_end).contains("\nnote:a:b\n"));
+  let result = session.subscription("/queue/a", |_: &Frame| Ack)
+    .with(AckMode::ClientIndividual)
+    .start();
+  assert_eq!(InvalidInput, result.unwrap_err().kind());
+  let _ = ::std::fs::remove_file(&path);
+}
+
+#[test]
+fn callbacks_and_receipt_handlers_survive_reconnecting() {
+  use std::cell::Cell;
+  use std::os::unix::net::UnixListener;
+  use std::sync::mpsc::channel;
+  use connection::UnixSocket;
+  let path = ::std::env::temp_dir().join(format!("stomp-rs-reconnect-{}.sock", ::std::process::id()));
+  let _ = ::std::fs::remove_file(&path);
+  let listener = UnixListener::bind(&path).unwrap();
+  let (send_error, error_requested) = channel::<()>();
+  let (error_written, wait_for_error) = channel::<()>();
+  // Every connection is ended with an ERROR frame on request, except the last one
+  let broker = thread::spawn(move || {
+    for connection in 0..3 {
+      let (mut stream, _) = listener.accept().unwrap();
+      assert!(read_frame(&mut stream).starts_with("CONNECT\n"));
+      stream.write_all(b"CONNECTED\nversion:1.2\n\n\0").unwrap();
+      if connection < 2 {
+        error_requested.recv().unwrap();
+        stream.write_all(b"ERROR\nmessage:Queue full\n\n\0").unwrap();
+        error_written.send(()).unwrap();
+      } else {
+        return read_frame(&mut stream);
+      }
+    }
+    unreachable!()
+  });
+
+  let errors = Cell::new(0);
+  let frames_sent = Cell::new(0);
+  let builder = SessionBuilder::new("localhost", 61613).with(UnixSocket(path.to_str().unwrap()));
+  let mut session = builder.start().unwrap();
+  session.on_error(|_: &BrokerError| errors.set(errors.get() + 1));
+  session.on_before_send(|_: &mut Frame| frames_sent.set(frames_sent.get() + 1));
+  session.receipt_handlers.insert("receipt/0".to_string(), Box::new(|_: &Frame| {}));
+  let mut event_loop = EventLoop::new().unwrap();
+  event_loop.register(&session.connection.stream, Token(0)).unwrap();
+  // Reconnected sessions don't block, so the ERROR frame has to be there before reading
+  for _ in 0..2 {
+    send_error.send(()).unwrap();
+    wait_for_error.recv().unwrap();
+    session.read_frames(&mut event_loop);
+  }
+  assert_eq!(2, errors.get());
+  assert_eq!(vec!["receipt/0"], session.outstanding_receipts());
+  session.message("/queue/a", "Animal").send().unwrap();
+  assert_eq!(1, frames_sent.get());
+  assert!(broker.join().unwrap().starts_with("SEND\n"));
+  let _ = ::std::fs::remove_file(&path);
+}
+
+#[test]
+fn refused_outbox_frames_are_not_resent() {
+  use std::cell::Cell;
+  use outbox::PersistentOutbox;
+  let path = ::std::env::temp_dir().join(format!("stomp-rs-refused-{}.journal", ::std::process::id()));
+  let _ = ::std::fs::remove_file(&path);
+  let removed_from_outbox = Cell::new(false);
+  let handler_called = Cell::new(false);
+  let builder = SessionBuilder::new("localhost", 61613).with(PersistentOutbox(path.to_str().unwrap()));
+  let (mut session, _broker_end) = session_over_socket_pair(builder, "1.2");
+  session.on_error(|broker_error: &BrokerError| removed_from_outbox.set(broker_error.removed_from_outbox));
+  session.message("/queue/a", "Animal").send().unwrap();
+  session.receipt_handlers.insert("outbox/0".to_string(), Box::new(|frame: &Frame| handler_called.set(frame.command == "ERROR")));
+  assert_eq!(1, session.outbox.as_ref().unwrap().len());
+
+  let mut error = Frame {
+    command: "ERROR".to_string(),
+    headers: HeaderList::new(),
+    body: Vec::new()
+  };
+  error.headers.push(header::Header::new("receipt-id", "outbox/0"));
+  session.dispatch(&mut error);
+  assert!(removed_from_outbox.get());
+  assert!(handler_called.get());
+  assert!(session.outstanding_receipts().is_empty());
+  assert_eq!(0, session.outbox.as_ref().unwrap().len());
+  let _ = ::std::fs::remove_file(&path);
+}
+
+#[test]
+fn unwritten_sends_are_requeued_after_reconnecting() {
+  use std::os::unix::net::UnixListener;
+  use connection::UnixSocket;
+  let path = ::std::env::temp_dir().join(format!("stomp-rs-requeue-{}.sock", ::std::process::id()));
+  let _ = ::std::fs::remove_file(&path);
+  let listener = UnixListener::bind(&path).unwrap();
+  let broker = thread::spawn(move || {
+    let mut streams = Vec::new();
+    for _ in 0..2 {
+      let (mut stream, _) = listener.accept().unwrap();
+      assert!(read_frame(&mut stream).starts_with("CONNECT\n"));
+      stream.write_all(b"CONNECTED\nversion:1.2\n\n\0").unwrap();
+      streams.push(stream);
+    }
+    read_frame(&mut streams[1])
+  });
+
+  let builder = SessionBuilder::new("localhost", 61613).with(UnixSocket(path.to_str().unwrap()));
+  let mut session = builder.start().unwrap();
+  let mut event_loop = EventLoop::new().unwrap();
+  event_loop.register(&session.connection.stream, Token(0)).unwrap();
+  // Neither frame has been written when the connection is lost
+  session.outbound.push_frame(&Frame::ack("message-1"));
+  session.outbound.push_frame(&Frame::send("/queue/a", "Animal".as_bytes()));
+  session.reconnect(&mut event_loop);
+  let requeued = broker.join().unwrap();
+  assert!(requeued.starts_with("SEND\n"));
+  assert!(requeued.contains("Animal"));
+  let _ = ::std::fs::remove_file(&path);
+}
+
+#[test]
+fn every_silent_interval_counts_as_a_missed_heartbeat() {
+  let (mut session, _broker_end) = session_over_socket_pair(SessionBuilder::new("localhost", 61613), "1.2");
+  session.rx_heartbeat_ms = 20;
+  session.last_read = Instant::now() - Duration::from_millis(50);
+  let mut event_loop = EventLoop::new().unwrap();
+  session.check_rx_heartbeat(&mut event_loop);
+  assert_eq!(1, session.stats().heartbeats_missed);
+  assert!(session.rx_heartbeat_timeout.is_some());
+  // The broker stays silent, so the re-armed timer reports the next interval as well
+  let deadline = Instant::now() + Duration::from_secs(2);
+  while session.stats().heartbeats_missed < 2 && Instant::now() < deadline {
+    event_loop.run_once(&mut session).unwrap();
+  }
+  assert_eq!(2, session.stats().heartbeats_missed);
+}
+
+#[test]
+fn discarded_frames_do_not_count_as_sent() {
+  let (mut session, mut broker_end) = session_over_socket_pair(SessionBuilder::new("localhost", 61613), "1.2");
+  session.start_batch();
+  let mut discarded = Frame::send("/queue/a", "Animal".as_bytes());
+  discarded.headers.push(header::Header::new("receipt", "receipt/0"));
+  session.send(discarded).unwrap();
+  session.discard_batch();
+  session.message("/queue/a", "Vegetable").send().unwrap();
+  assert!(read_frame(&mut broker_end).contains("Vegetable"));
+  let stats = session.stats();
+  assert_eq!(1, stats.sent["SEND"].frames);
+  assert_eq!(1, stats.sent_per_destination["/queue/a"].frames);
+  assert_eq!(0, stats.outstanding_receipts);
+}
+
+#[test]
+fn streamed_messages_are_nacked_instead_of_dead_lettered() {
+  use streaming::{streaming, StreamingThreshold};
+  use dead_letter::DeadLetter;
+  let builder = SessionBuilder::new("localhost", 61613).with(StreamingThreshold(4));
+  let (mut session, mut broker_end) = session_over_socket_pair(builder, "1.2");
+  session.subscription("/queue/a", streaming(|_: &Frame, body: &mut Read| {
+      let mut content = String::new();
+      body.read_to_string(&mut content).unwrap();
+      assert_eq!("Vegetable", content);
+      Nack
+    }))
+    .with(AckMode::Client)
+    .with(DeadLetter::new("/queue/dead", 1))
+    .start()
+    .unwrap();
+  assert!(read_frame(&mut broker_end).starts_with("SUBSCRIBE\n"));
+  broker_end.write_all(b"MESSAGE\ndestination:/queue/a\nmessage-id:1\nsubscription:stomp-rs/0\nack:1\ncontent-length:9\n\nVegetable\0").unwrap();
+  let mut event_loop = EventLoop::new().unwrap();
+  session.read_frames(&mut event_loop);
+  assert!(read_frame(&mut broker_end).starts_with("NACK\nid:1\n"));
+}
+
+#[test]
+fn dead_lettered_messages_are_acked_once_the_copy_is_received() {
+  use dead_letter::DeadLetter;
+  let (mut session, mut broker_end) = session_over_socket_pair(SessionBuilder::new("localhost", 61613), "1.2");
+  session.subscription("/queue/a", |_: &Frame| Nack)
+    .with(AckMode::Client)
+    .with(DeadLetter::new("/queue/dead", 1))
+    .start()
+    .unwrap();
+  assert!(read_frame(&mut broker_end).starts_with("SUBSCRIBE
+"));
+  broker_end.write_all(b"MESSAGE\ndestination:/queue/a\nmessage-id:1\nsubscription:stomp-rs/0\nack:1\n\nAnimal\0").unwrap();
+  let mut event_loop = EventLoop::new().unwrap();
+  session.read_frames(&mut event_loop);
+  let dead_letter = read_frame(&mut broker_end);
+  assert!(dead_letter.starts_with("SEND\n"));
+  assert!(dead_letter.contains("destination:/queue/dead\n"));
+  assert!(dead_letter.contains("receipt:dead-letter/0\n"));
+  assert!(session.outbound.is_empty());
+
+  broker_end.write_all(b"RECEIPT\nreceipt-id:dead-letter/0\n\n\0").unwrap();
+  session.read_frames(&mut event_loop);
+  assert!(read_frame(&mut broker_end).starts_with("ACK\nid:1\n"));
+}
+
+#[test]
+fn streamed_sends_are_refused_inside_batches_with_an_outbox_or_a_delay() {
+  use outbox::PersistentOutbox;
+  use scheduling::Delay;
+  let (mut session, _broker_end) = session_over_socket_pair(SessionBuilder::new("localhost", 61613), "1.2");
+  {
+    let batch = session.batch();
+    let error = batch.session.message_stream("/queue/a", "Animal".as_bytes(), 6).send().err().unwrap();
+    assert_eq!(InvalidInput, error.kind());
+  }
+  let error = session.message_stream("/queue/a", "Animal".as_bytes(), 6)
+    .with(Delay(Duration::from_secs(60)))
+    .send()
+    .err().unwrap();
+  assert_eq!(InvalidInput, error.kind());
+
+  let path = ::std::env::temp_dir().join(format!("stomp-rs-streamed-{}.journal", ::std::process::id()));
+  let _ = ::std::fs::remove_file(&path);
+  let builder = SessionBuilder::new("localhost", 61613).with(PersistentOutbox(path.to_str().unwrap()));
+  let (mut session, _broker_end) = session_over_socket_pair(builder, "1.2");
+  let error = session.message_stream("/queue/a", "Animal".as_bytes(), 6).send().err().unwrap();
+  assert_eq!(InvalidInput, error.kind());
+  let _ = ::std::fs::remove_file(&path);
 }
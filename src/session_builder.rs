@@ -2,8 +2,21 @@ use session::Session;
 use frame::Frame;
 use option_setter::OptionSetter;
 use std::io::Result;
-use connection::{Connection, HeartBeat, Credentials};
-use header::{HeaderList, Header};
+use std::io::Error;
+use std::io::ErrorKind::{InvalidInput, InvalidData};
+use connection::{self, Connection, HeartBeat, Credentials, UnixSocket, Timeouts};
+use connection::{DEFAULT_HEARTBEAT_SEND_FRACTION, DEFAULT_HEARTBEAT_GRACE};
+use header::{HeaderList, Header, StompVersion};
+use outbox::{Outbox, PersistentOutbox};
+use compression::Compression;
+use scheduling::Broker;
+use proxy::Proxy;
+use broker_error::BrokerErrorPolicy;
+use transport::Transport;
+#[cfg(feature = "tls")]
+use tls::Tls;
+#[cfg(feature = "websocket")]
+use websocket::WebSocket;
 
 #[derive(Clone)]
 pub struct SessionBuilder<'a> {
@@ -11,7 +24,26 @@ pub struct SessionBuilder<'a> {
   pub port: u16,
   pub credentials: Option<Credentials<'a>>,
   pub heartbeat: HeartBeat,
-  pub headers: HeaderList
+  pub heartbeat_send_fraction: f64,
+  pub heartbeat_grace: f64,
+  // Versions offered in the CONNECT frame; the broker picks the highest one it supports
+  pub accept_versions: Vec<StompVersion>,
+  pub headers: HeaderList,
+  pub outbox: Option<PersistentOutbox<'a>>,
+  pub outbound_high_water_mark: Option<usize>,
+  pub streaming_threshold: Option<usize>,
+  pub compression: Option<Compression>,
+  pub broker: Broker,
+  pub broker_error_policy: BrokerErrorPolicy,
+  pub unix_socket: Option<UnixSocket<'a>>,
+  pub timeouts: Timeouts,
+  pub proxy: Option<Proxy>,
+  #[cfg(feature = "tls")]
+  pub tls: Option<Tls>,
+  #[cfg(feature = "websocket")]
+  pub websocket: Option<WebSocket>,
+  #[cfg(feature = "prometheus")]
+  pub metrics_endpoint: Option<String>
 }
 
 impl <'a> SessionBuilder <'a> {
@@ -21,16 +53,43 @@ impl <'a> SessionBuilder <'a> {
       port: port,
       credentials: None,
       heartbeat: HeartBeat(0,0),
+      heartbeat_send_fraction: DEFAULT_HEARTBEAT_SEND_FRACTION,
+      heartbeat_grace: DEFAULT_HEARTBEAT_GRACE,
+      accept_versions: vec![StompVersion::Stomp_v1_0, StompVersion::Stomp_v1_1, StompVersion::Stomp_v1_2],
       headers: header_list![ 
        "host" => host,
-       "accept-version" => "1.2",
        "content-length" => "0"
-      ] 
+      ],
+      outbox: None,
+      outbound_high_water_mark: None,
+      streaming_threshold: None,
+      compression: None,
+      broker: Broker::Generic,
+      broker_error_policy: BrokerErrorPolicy::Reconnect,
+      unix_socket: None,
+      timeouts: Timeouts::default(),
+      proxy: None,
+      #[cfg(feature = "tls")]
+      tls: None,
+      #[cfg(feature = "websocket")]
+      websocket: None,
+      #[cfg(feature = "prometheus")]
+      metrics_endpoint: None
     }
   }
 
   #[allow(dead_code)] 
-  pub fn start(mut self) -> Result<Session<'a>> {
+  pub fn start(self) -> Result<Session<'a>> {
+    self.start_over(|session_builder| session_builder.connect())
+  }
+
+  // Runs the session over a transport opened by the application, e.g. a tunnel or an
+  // in-memory pipe. The transport's reopen() is used whenever the session reconnects.
+  pub fn start_with_transport(self, transport: Box<Transport>) -> Result<Session<'a>> {
+    self.start_over(move |session_builder| Ok(Connection::with_transport(session_builder.host, session_builder.port, transport)))
+  }
+
+  fn start_over<F>(mut self, open_connection: F) -> Result<Session<'a>> where F: FnOnce(&SessionBuilder<'a>) -> Result<Connection> {
     // Add credentials to the header list if specified
     match self.credentials {
       Some(Credentials(ref login, ref passcode)) => {
@@ -41,6 +100,9 @@ impl <'a> SessionBuilder <'a> {
       None => debug!("No credentials supplied.")
     }
     
+    let accept_version: Vec<&str> = self.accept_versions.iter().map(|version| version.as_str()).collect();
+    self.headers.push(Header::new("accept-version", &accept_version.join(",")));
+
     let HeartBeat(client_tx_ms, client_rx_ms) = self.heartbeat;
     let heart_beat_string = format!("{},{}", client_tx_ms, client_rx_ms);
     debug!("Using heartbeat: {},{}", client_tx_ms, client_rx_ms);
@@ -52,8 +114,22 @@ impl <'a> SessionBuilder <'a> {
       body : Vec::new()
     };
 
-    let mut connection = try!(Connection::new(self.host, self.port));
+    // Opening the outbox first means a bad journal path fails before we connect
+    let outbox = match self.outbox {
+      Some(PersistentOutbox(path)) => Some(try!(Outbox::open(path))),
+      None => None
+    };
+
+    let mut connection = try!(open_connection(&self).map_err(connection::handshake_error));
+    try!(connection.stream.set_read_timeout(self.timeouts.handshake));
+    try!(connection.stream.set_write_timeout(self.timeouts.handshake));
     let (server_tx_ms, server_rx_ms) = try!(connection.start_session_with_frame(connect_frame));
+    if !self.accept_versions.contains(&connection.version) {
+      return Err(Error::new(InvalidData, format!("Server chose STOMP {}, which was not offered.", connection.version.as_str())));
+    }
+    info!("Connected to {} using STOMP {}, session id {:?}.", connection.server.as_ref().map(|server| server.as_ref()).unwrap_or("an unnamed server"), connection.version.as_str(), connection.session_id);
+    try!(connection.stream.set_read_timeout(None));
+    try!(connection.stream.set_write_timeout(self.timeouts.write));
     let (tx_ms, rx_ms) = Connection::select_heartbeat(
       client_tx_ms,
       client_rx_ms,
@@ -61,7 +137,43 @@ impl <'a> SessionBuilder <'a> {
       server_rx_ms
     );
 
-    Ok(Session::new(self, connection, tx_ms, rx_ms))
+    let mut session = Session::new(self, connection, tx_ms, rx_ms);
+    session.outbox = outbox;
+    session.hold_outbox_frames();
+    try!(session.resend_outbox());
+    Ok(session)
+  }
+
+  fn connect(&self) -> Result<Connection> {
+    if self.unix_socket.is_some() && self.proxy.is_some() {
+      return Err(Error::new(InvalidInput, "Proxies are not supported for Unix domain sockets."));
+    }
+    let connection = try!(self.open_socket());
+    #[cfg(feature = "websocket")]
+    {
+      if let Some(ref websocket) = self.websocket {
+        return connection.upgrade_to_websocket(websocket);
+      }
+    }
+    Ok(connection)
+  }
+
+  #[cfg(feature = "tls")]
+  fn open_socket(&self) -> Result<Connection> {
+    match (self.unix_socket, self.tls.as_ref()) {
+      (Some(_), Some(_)) => Err(Error::new(InvalidInput, "TLS is not supported over Unix domain sockets.")),
+      (Some(UnixSocket(path)), None) => Connection::new_unix(path, self.timeouts),
+      (None, Some(tls)) => Connection::new_tls(self.host, self.port, tls, self.timeouts, self.proxy.as_ref()),
+      (None, None) => Connection::new(self.host, self.port, self.timeouts, self.proxy.as_ref())
+    }
+  }
+
+  #[cfg(not(feature = "tls"))]
+  fn open_socket(&self) -> Result<Connection> {
+    match self.unix_socket {
+      Some(UnixSocket(path)) => Connection::new_unix(path, self.timeouts),
+      None => Connection::new(self.host, self.port, self.timeouts, self.proxy.as_ref())
+    }
   }
 
   #[allow(dead_code)] 
@@ -0,0 +1,347 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use frame::Frame;
+use header::{StompHeaderSet, Destination, Receipt, ContentLength};
+use scheduling::duration_to_millis;
+
+// Upper bounds of the receipt round-trip histogram buckets; slower receipts land in a final bucket
+pub const RECEIPT_LATENCY_BOUNDS_MS: [u64; 12] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Traffic {
+  pub frames: u64,
+  // Encoded size, including the null octet that ends each frame
+  pub bytes: u64
+}
+
+impl Traffic {
+  fn record(&mut self, bytes: usize) {
+    self.frames += 1;
+    self.bytes += bytes as u64;
+  }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct SubscriptionStats {
+  pub destination: String,
+  pub messages: Traffic,
+  pub acks: u64,
+  pub nacks: u64
+}
+
+#[derive(Clone, Debug)]
+pub struct LatencyHistogram {
+  pub bounds_ms: &'static [u64],
+  // One count per bound plus one for everything slower; not cumulative
+  pub counts: Vec<u64>,
+  pub count: u64,
+  pub sum_ms: u64
+}
+
+impl LatencyHistogram {
+  pub fn new(bounds_ms: &'static [u64]) -> LatencyHistogram {
+    LatencyHistogram {
+      bounds_ms: bounds_ms,
+      counts: vec![0; bounds_ms.len() + 1],
+      count: 0,
+      sum_ms: 0
+    }
+  }
+
+  pub fn record(&mut self, latency: Duration) {
+    let latency_ms = duration_to_millis(latency);
+    let bucket = self.bounds_ms.iter().position(|&bound| latency_ms <= bound).unwrap_or(self.bounds_ms.len());
+    self.counts[bucket] += 1;
+    self.count += 1;
+    self.sum_ms += latency_ms;
+  }
+}
+
+// A snapshot of what a session has done since it was created, across reconnects
+#[derive(Clone, Debug)]
+pub struct SessionStats {
+  // Keyed by command
+  pub sent: HashMap<String, Traffic>,
+  pub received: HashMap<String, Traffic>,
+  // SEND frames by destination
+  pub sent_per_destination: HashMap<String, Traffic>,
+  // MESSAGE frames by destination
+  pub received_per_destination: HashMap<String, Traffic>,
+  pub acks: u64,
+  pub nacks: u64,
+  pub heartbeats_sent: u64,
+  pub heartbeats_received: u64,
+  // Times the broker was silent for longer than the heartbeat grace period
+  pub heartbeats_missed: u64,
+  pub reconnects: u64,
+  pub outstanding_receipts: usize,
+  pub receipt_latency: LatencyHistogram,
+  // The most bytes the frame buffer has held while waiting for frames to complete
+  pub frame_buffer_high_water_mark: usize,
+  // Keyed by subscription id
+  pub subscriptions: HashMap<String, SubscriptionStats>
+}
+
+impl SessionStats {
+  pub fn new() -> SessionStats {
+    SessionStats {
+      sent: HashMap::new(),
+      received: HashMap::new(),
+      sent_per_destination: HashMap::new(),
+      received_per_destination: HashMap::new(),
+      acks: 0,
+      nacks: 0,
+      heartbeats_sent: 0,
+      heartbeats_received: 0,
+      heartbeats_missed: 0,
+      reconnects: 0,
+      outstanding_receipts: 0,
+      receipt_latency: LatencyHistogram::new(&RECEIPT_LATENCY_BOUNDS_MS),
+      frame_buffer_high_water_mark: 0,
+      subscriptions: HashMap::new()
+    }
+  }
+}
+
+// What is counted once a queued frame has been written
+struct QueuedFrame {
+  command: String,
+  destination: Option<String>,
+  receipt_id: Option<String>,
+  bytes: usize
+}
+
+impl QueuedFrame {
+  fn new(frame: &Frame, body_length: usize) -> QueuedFrame {
+    QueuedFrame {
+      command: frame.command.clone(),
+      destination: frame.headers.get_destination().map(|Destination(destination)| destination.to_string()),
+      receipt_id: frame.headers.get_receipt().map(|Receipt(receipt_id)| receipt_id.to_string()),
+      bytes: frame.count_bytes() - frame.body.len() + body_length + 1
+    }
+  }
+}
+
+// Kept by the session and carried over when it reconnects
+pub struct StatsRecorder {
+  stats: SessionStats,
+  receipts_requested_at: HashMap<String, Instant>,
+  // Frames in the outbound queue, in the order they will be written
+  queued_frames: VecDeque<QueuedFrame>
+}
+
+impl StatsRecorder {
+  pub fn new() -> StatsRecorder {
+    StatsRecorder {
+      stats: SessionStats::new(),
+      receipts_requested_at: HashMap::new(),
+      queued_frames: VecDeque::new()
+    }
+  }
+
+  pub fn snapshot(&self) -> SessionStats {
+    let mut stats = self.stats.clone();
+    stats.outstanding_receipts = self.receipts_requested_at.len();
+    stats
+  }
+
+  pub fn on_frame_sent(&mut self, frame: &Frame) {
+    self.on_frame_sent_with_body(frame, frame.body.len());
+  }
+
+  // For frames whose body is not held by the frame itself
+  pub fn on_frame_sent_with_body(&mut self, frame: &Frame, body_length: usize) {
+    self.record_sent(QueuedFrame::new(frame, body_length));
+  }
+
+  // Queued frames only count as sent once on_frames_written() says so
+  pub fn on_frame_queued(&mut self, frame: &Frame) {
+    self.queued_frames.push_back(QueuedFrame::new(frame, frame.body.len()));
+  }
+
+  pub fn on_frames_written(&mut self, count: usize) {
+    for _ in 0..count {
+      match self.queued_frames.pop_front() {
+        Some(queued_frame) => self.record_sent(queued_frame),
+        None => return
+      }
+    }
+  }
+
+  // Forgets the frames that were dropped from the outbound queue, keeping the first `remaining`
+  pub fn on_frames_discarded(&mut self, remaining: usize) {
+    self.queued_frames.truncate(remaining);
+  }
+
+  fn record_sent(&mut self, frame: QueuedFrame) {
+    record(&mut self.stats.sent, &frame.command, frame.bytes);
+    match frame.command.as_ref() {
+      "SEND" => if let Some(ref destination) = frame.destination {
+        record(&mut self.stats.sent_per_destination, destination, frame.bytes);
+      },
+      "ACK" => self.stats.acks += 1,
+      "NACK" => self.stats.nacks += 1,
+      _ => {}
+    }
+    if let Some(receipt_id) = frame.receipt_id {
+      self.receipts_requested_at.insert(receipt_id, Instant::now());
+    }
+  }
+
+  // `destination` is the one the subscription was made for, which wildcards can make differ
+  // from the message's own
+  pub fn on_frame_received(&mut self, frame: &Frame, subscription: Option<(&str, &str)>) {
+    let body_length = match frame.headers.get_content_length() {
+      Some(ContentLength(length)) => length as usize,
+      None => frame.body.len()
+    };
+    let bytes = frame.count_bytes() - frame.body.len() + body_length + 1;
+    record(&mut self.stats.received, &frame.command, bytes);
+    if frame.command != "MESSAGE" {
+      return;
+    }
+    if let Some(Destination(destination)) = frame.headers.get_destination() {
+      record(&mut self.stats.received_per_destination, destination, bytes);
+    }
+    if let Some((id, destination)) = subscription {
+      self.subscription(id, destination).messages.record(bytes);
+    }
+  }
+
+  pub fn on_settled(&mut self, subscription: (&str, &str), command: &str) {
+    let subscription_stats = self.subscription(subscription.0, subscription.1);
+    match command {
+      "ACK" => subscription_stats.acks += 1,
+      _ => subscription_stats.nacks += 1
+    }
+  }
+
+  pub fn on_receipt(&mut self, receipt_id: &str) {
+    if let Some(requested_at) = self.receipts_requested_at.remove(receipt_id) {
+      self.stats.receipt_latency.record(requested_at.elapsed());
+    }
+  }
+
+  // The broker answered with an ERROR frame instead, so there is no latency to record
+  pub fn on_receipt_refused(&mut self, receipt_id: &str) {
+    self.receipts_requested_at.remove(receipt_id);
+  }
+
+  pub fn on_heartbeat_sent(&mut self) {
+    self.stats.heartbeats_sent += 1;
+  }
+
+  pub fn on_heartbeat_received(&mut self) {
+    self.stats.heartbeats_received += 1;
+  }
+
+  pub fn on_heartbeat_missed(&mut self) {
+    self.stats.heartbeats_missed += 1;
+  }
+
+  pub fn on_frame_buffer_len(&mut self, len: usize) {
+    if len > self.stats.frame_buffer_high_water_mark {
+      self.stats.frame_buffer_high_water_mark = len;
+    }
+  }
+
+  // Receipts requested over the old connection will never arrive, and frames queued for it
+  // were never written
+  pub fn on_reconnect(&mut self) {
+    self.stats.reconnects += 1;
+    self.receipts_requested_at.clear();
+    self.queued_frames.clear();
+  }
+
+  fn subscription(&mut self, id: &str, destination: &str) -> &mut SubscriptionStats {
+    self.stats.subscriptions.entry(id.to_string()).or_insert_with(|| SubscriptionStats {
+      destination: destination.to_string(),
+      .. SubscriptionStats::default()
+    })
+  }
+}
+
+fn record(traffic: &mut HashMap<String, Traffic>, key: &str, bytes: usize) {
+  if let Some(counters) = traffic.get_mut(key) {
+    counters.record(bytes);
+    return;
+  }
+  let mut counters = Traffic::default();
+  counters.record(bytes);
+  traffic.insert(key.to_string(), counters);
+}
+
+#[test]
+fn stats_count_traffic_and_time_receipts() {
+  use header::{Header, HeaderList};
+  let mut recorder = StatsRecorder::new();
+  let mut send_frame = Frame::send("/queue/a", "Animal".as_bytes());
+  send_frame.headers.push(Header::new("receipt", "message-1"));
+  recorder.on_frame_sent(&send_frame);
+  recorder.on_frame_sent(&Frame::send("/queue/a", "Mineral".as_bytes()));
+  recorder.on_frame_sent(&Frame::ack("message-2"));
+  let stats = recorder.snapshot();
+  assert_eq!(2, stats.sent["SEND"].frames);
+  assert_eq!((send_frame.count_bytes() + 1 + Frame::send("/queue/a", "Mineral".as_bytes()).count_bytes() + 1) as u64, stats.sent["SEND"].bytes);
+  assert_eq!(2, stats.sent_per_destination["/queue/a"].frames);
+  assert_eq!(1, stats.acks);
+  assert_eq!(1, stats.outstanding_receipts);
+
+  let message = Frame {
+    command: "MESSAGE".to_string(),
+    headers: header_list![
+      "destination" => "/topic/b"
+    ],
+    body: "Vegetable".as_bytes().to_vec()
+  };
+  recorder.on_frame_received(&message, Some(("stomp-rs/0", "/topic/*")));
+  recorder.on_settled(("stomp-rs/0", "/topic/*"), "NACK");
+  recorder.on_receipt("message-1");
+  recorder.on_receipt("unknown");
+  let stats = recorder.snapshot();
+  assert_eq!(1, stats.received_per_destination["/topic/b"].frames);
+  assert_eq!("/topic/*", stats.subscriptions["stomp-rs/0"].destination);
+  assert_eq!(1, stats.subscriptions["stomp-rs/0"].messages.frames);
+  assert_eq!(1, stats.subscriptions["stomp-rs/0"].nacks);
+  assert_eq!(0, stats.outstanding_receipts);
+  assert_eq!(1, stats.receipt_latency.count);
+  assert_eq!(1, stats.receipt_latency.counts.iter().sum::<u64>());
+
+  recorder.on_frame_sent(&send_frame);
+  recorder.on_reconnect();
+  let stats = recorder.snapshot();
+  assert_eq!(1, stats.reconnects);
+  assert_eq!(0, stats.outstanding_receipts);
+  assert_eq!(3, stats.sent["SEND"].frames);
+}
+
+#[test]
+fn latencies_land_in_the_first_bucket_that_fits() {
+  let mut histogram = LatencyHistogram::new(&RECEIPT_LATENCY_BOUNDS_MS);
+  histogram.record(Duration::from_millis(1));
+  histogram.record(Duration::from_millis(7));
+  histogram.record(Duration::from_secs(60));
+  assert_eq!(1, histogram.counts[0]);
+  assert_eq!(1, histogram.counts[3]);
+  assert_eq!(1, histogram.counts[RECEIPT_LATENCY_BOUNDS_MS.len()]);
+  assert_eq!(60_008, histogram.sum_ms);
+}
+
+#[test]
+fn queued_frames_count_once_written() {
+  let mut recorder = StatsRecorder::new();
+  recorder.on_frame_queued(&Frame::ack("message-1"));
+  recorder.on_frame_queued(&Frame::nack("message-2"));
+  recorder.on_frame_queued(&Frame::ack("message-3"));
+  assert!(recorder.snapshot().sent.is_empty());
+  recorder.on_frames_written(1);
+  recorder.on_frames_discarded(1);
+  recorder.on_frames_written(1);
+  let stats = recorder.snapshot();
+  assert_eq!(1, stats.acks);
+  assert_eq!(1, stats.nacks);
+  recorder.on_frame_queued(&Frame::ack("message-4"));
+  recorder.on_reconnect();
+  recorder.on_frames_written(1);
+  assert_eq!(1, recorder.snapshot().acks);
+}
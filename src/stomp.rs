@@ -7,6 +7,19 @@ extern crate log;
 extern crate mio;
 extern crate lifeguard;
 extern crate unicode_segmentation;
+#[cfg(any(feature = "gzip", feature = "deflate"))]
+extern crate flate2;
+#[cfg(feature = "zstd")]
+extern crate zstd;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+extern crate serde_json;
+#[cfg(feature = "tls")]
+extern crate native_tls;
+#[cfg(feature = "websocket")]
+extern crate sha1_smol;
+extern crate base64;
 
 use session_builder::SessionBuilder;
 
@@ -14,14 +27,34 @@ pub fn session<'a>(host: &'a str, port: u16) -> SessionBuilder<'a>{
   SessionBuilder::new(host, port)
 }
 
+pub mod transport;
 pub mod connection;
+pub mod proxy;
+pub mod broker_error;
 pub mod header;
 pub mod frame;
 pub mod frame_buffer;
 pub mod session;
 pub mod subscription;
 pub mod transaction;
+pub mod batch;
+pub mod outbound_queue;
+pub mod streaming;
+pub mod compression;
+pub mod scheduling;
+pub mod stats;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+#[cfg(feature = "serde")]
+pub mod codec;
 pub mod message_builder;
 pub mod session_builder;
 pub mod subscription_builder;
 pub mod option_setter;
+pub mod outbox;
+pub mod deduplication;
+pub mod dead_letter;
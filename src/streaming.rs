@@ -0,0 +1,122 @@
+use std::io::Read;
+use std::io::Result;
+use std::io::Error;
+use std::io::ErrorKind::UnexpectedEof;
+use std::cmp::min;
+use frame::Frame;
+use frame_buffer::FrameBuffer;
+use subscription::{AckOrNack, MessageHandler};
+
+const DISCARD_BUFFER_SIZE: usize = 8 * 1024;
+
+// MESSAGE bodies of at least this many bytes are streamed to handlers instead of buffered
+#[derive(Clone, Copy)]
+pub struct StreamingThreshold(pub usize);
+
+pub struct BodyReader<'a, R: Read + 'a> {
+  frame_buffer: &'a mut FrameBuffer,
+  stream: &'a mut R
+}
+
+impl <'a, R: Read + 'a> BodyReader<'a, R> {
+  pub fn new(frame_buffer: &'a mut FrameBuffer, stream: &'a mut R) -> BodyReader<'a, R> {
+    BodyReader {
+      frame_buffer: frame_buffer,
+      stream: stream
+    }
+  }
+
+  // Discards whatever the handler didn't read, including the trailing null octet
+  pub fn finish(self) -> Result<()> {
+    let mut discard_buffer = [0u8; DISCARD_BUFFER_SIZE];
+    loop {
+      self.frame_buffer.skip_streamed_body();
+      let remaining = self.frame_buffer.streamed_bytes_remaining();
+      if remaining == 0 {
+        return Ok(());
+      }
+      let limit = min(remaining, discard_buffer.len());
+      let bytes_read = try!(self.stream.read(&mut discard_buffer[..limit]));
+      if bytes_read == 0 {
+        return Err(Error::new(UnexpectedEof, "Connection closed in the middle of a message body."));
+      }
+      self.frame_buffer.consume_streamed_bytes(bytes_read);
+    }
+  }
+}
+
+impl <'a, R: Read + 'a> Read for BodyReader<'a, R> {
+  fn read(&mut self, destination: &mut [u8]) -> Result<usize> {
+    let content_remaining = self.frame_buffer.streamed_bytes_remaining().saturating_sub(1);
+    if content_remaining == 0 || destination.len() == 0 {
+      return Ok(0);
+    }
+    // Bytes that already made it into the frame buffer come first
+    let buffered = self.frame_buffer.read_streamed_body(destination);
+    if buffered > 0 {
+      return Ok(buffered);
+    }
+    let limit = min(destination.len(), content_remaining);
+    let bytes_read = try!(self.stream.read(&mut destination[..limit]));
+    if bytes_read == 0 {
+      return Err(Error::new(UnexpectedEof, "Connection closed in the middle of a message body."));
+    }
+    self.frame_buffer.consume_streamed_bytes(bytes_read);
+    Ok(bytes_read)
+  }
+}
+
+pub struct Streaming<F> {
+  handler: F
+}
+
+pub fn streaming<F>(handler: F) -> Streaming<F> where F: FnMut(&Frame, &mut Read) -> AckOrNack {
+  Streaming {
+    handler: handler
+  }
+}
+
+impl <F> MessageHandler for Streaming<F> where F: FnMut(&Frame, &mut Read) -> AckOrNack {
+  fn on_message(&mut self, frame: &Frame) -> AckOrNack {
+    // Small bodies are buffered anyway; hand them out through the same interface
+    let mut body: &[u8] = frame.body.as_ref();
+    (self.handler)(frame, &mut body)
+  }
+
+  fn on_message_stream(&mut self, frame: &mut Frame, body: &mut Read) -> AckOrNack {
+    (self.handler)(frame, body)
+  }
+}
+
+#[test]
+fn body_reader_reads_buffered_bytes_then_the_stream() {
+  let mut frame_buffer = FrameBuffer::new();
+  frame_buffer.stream_bodies_over(Some(4));
+  frame_buffer.append("MESSAGE\ncontent-length:10\n\n0123".as_bytes());
+  match frame_buffer.read_transmission() {
+    Some(::frame::Transmission::StreamingFrame(frame)) => assert!(frame.body.is_empty()),
+    _ => panic!("Expected a streaming frame.")
+  }
+  let mut rest_of_connection: &[u8] = b"456789\0MESSAGE";
+  let mut body = Vec::new();
+  {
+    let mut reader = BodyReader::new(&mut frame_buffer, &mut rest_of_connection);
+    reader.read_to_end(&mut body).unwrap();
+    reader.finish().unwrap();
+  }
+  assert_eq!(b"0123456789", &body[..]);
+  assert_eq!(0, frame_buffer.streamed_bytes_remaining());
+  assert_eq!(b"MESSAGE", rest_of_connection);
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn encoded_bodies_are_buffered_for_decoding() {
+  let mut frame_buffer = FrameBuffer::new();
+  frame_buffer.stream_bodies_over(Some(4));
+  frame_buffer.append("MESSAGE\ncontent-encoding:gzip\ncontent-length:10\n\n0123456789\0".as_bytes());
+  match frame_buffer.read_transmission() {
+    Some(::frame::Transmission::CompleteFrame(frame)) => assert_eq!(b"0123456789", &frame.body[..]),
+    _ => panic!("Expected a complete frame.")
+  }
+}
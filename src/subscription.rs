@@ -2,6 +2,9 @@ use frame::Frame;
 use subscription::AckOrNack::{Ack, Nack};
 use header::HeaderList;
 use std::sync::mpsc::Sender;
+use std::io::Read;
+use deduplication::DuplicateFilter;
+use dead_letter::RedeliveryTracker;
 
 #[derive(Copy,Clone)]
 pub enum AckMode {
@@ -28,6 +31,16 @@ pub enum AckOrNack {
 
 pub trait MessageHandler {
   fn on_message(&mut self, &Frame) -> AckOrNack;
+
+  // Called for frames whose body is still on the connection. By default the body is
+  // collected into the frame before calling on_message.
+  fn on_message_stream(&mut self, frame: &mut Frame, body: &mut Read) -> AckOrNack {
+    if let Err(error) = body.read_to_end(&mut frame.body) {
+      error!("Could not read message body: {}", error);
+      return Nack;
+    }
+    self.on_message(frame)
+  }
 }
 
 pub struct Subscription <'a> { 
@@ -35,7 +48,9 @@ pub struct Subscription <'a> {
   pub destination: String,
   pub ack_mode: AckMode,
   pub headers: HeaderList,
-  pub handler: Box<MessageHandler + 'a>
+  pub handler: Box<MessageHandler + 'a>,
+  pub duplicate_filter: Option<DuplicateFilter>,
+  pub redelivery_tracker: Option<RedeliveryTracker>
 }
 
 impl <'a> Subscription <'a> {
@@ -45,7 +60,9 @@ impl <'a> Subscription <'a> {
       destination: destination.to_string(),
       ack_mode: ack_mode,
       headers: headers,
-      handler: message_handler
+      handler: message_handler,
+      duplicate_filter: None,
+      redelivery_tracker: None
     }
   }
 }
@@ -1,24 +1,36 @@
 use session::Session;
 use subscription::{Subscription, MessageHandler, AckMode};
 use frame::Frame;
-use header::HeaderList;
+use header::{HeaderList, StompVersion};
 use option_setter::OptionSetter;
+use deduplication::{Deduplication, DuplicateFilter};
+use dead_letter::{DeadLetter, RedeliveryTracker};
 use std::io::Result;
+use std::io::Error;
+use std::io::ErrorKind::InvalidInput;
 
 pub struct SubscriptionBuilder <'a, 'session: 'a, 'sub: 'session> {
   pub session: &'a mut Session<'session>,
   pub destination: &'a str,
   pub ack_mode: AckMode,
   pub handler: Box<MessageHandler + 'sub>,
-  pub headers: HeaderList
+  pub headers: HeaderList,
+  pub deduplication: Option<Deduplication>,
+  pub dead_letter: Option<DeadLetter>
 }
 
 impl <'a, 'session, 'sub> SubscriptionBuilder <'a, 'session, 'sub> {
 
   #[allow(dead_code)] 
   pub fn start(mut self) -> Result<String> {
+    // STOMP 1.0 only knows the 'auto' and 'client' ack modes
+    if let (AckMode::ClientIndividual, StompVersion::Stomp_v1_0) = (self.ack_mode, self.session.version()) {
+      return Err(Error::new(InvalidInput, "STOMP 1.0 does not support the client-individual ack mode."));
+    }
     let next_id = self.session.generate_subscription_id();
-    let subscription = Subscription::new(next_id, self.destination, self.ack_mode, self.headers.clone(), self.handler);
+    let mut subscription = Subscription::new(next_id, self.destination, self.ack_mode, self.headers.clone(), self.handler);
+    subscription.duplicate_filter = self.deduplication.map(DuplicateFilter::new);
+    subscription.redelivery_tracker = self.dead_letter.map(RedeliveryTracker::new);
     let mut subscribe_frame = Frame::subscribe(&subscription.id, self.destination, self.ack_mode);
 
     subscribe_frame.headers.concat(&mut self.headers);
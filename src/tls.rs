@@ -0,0 +1,205 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::io::Result;
+use std::io::Error;
+use std::io::ErrorKind::{InvalidData, WouldBlock};
+use std::time::Duration;
+use std::net::{TcpStream, Shutdown};
+use std::os::unix::io::{AsRawFd, RawFd};
+use native_tls::{Certificate, HandshakeError, Identity, TlsConnector, TlsStream};
+use transport::Transport;
+use connection::{self, Timeouts};
+use proxy::Proxy;
+
+#[derive(Clone)]
+pub struct Tls {
+  // PEM files with additional certificate authorities to trust
+  pub ca_bundles: Vec<String>,
+  // Name to send for SNI and verify the certificate against, if not the host we connect to
+  pub server_name: Option<String>,
+  // PEM encoded certificate chain and PKCS #8 private key for mutual TLS
+  pub client_certificate: Option<(String, String)>,
+  pub use_sni: bool,
+  pub verify_hostname: bool,
+  pub verify_certificate: bool
+}
+
+impl Tls {
+  pub fn new() -> Tls {
+    Tls {
+      ca_bundles: Vec::new(),
+      server_name: None,
+      client_certificate: None,
+      use_sni: true,
+      verify_hostname: true,
+      verify_certificate: true
+    }
+  }
+
+  pub fn ca_bundle(mut self, path: &str) -> Tls {
+    self.ca_bundles.push(path.to_string());
+    self
+  }
+
+  pub fn server_name(mut self, server_name: &str) -> Tls {
+    self.server_name = Some(server_name.to_string());
+    self
+  }
+
+  pub fn client_certificate(mut self, certificate_path: &str, private_key_path: &str) -> Tls {
+    self.client_certificate = Some((certificate_path.to_string(), private_key_path.to_string()));
+    self
+  }
+
+  pub fn use_sni(mut self, use_sni: bool) -> Tls {
+    self.use_sni = use_sni;
+    self
+  }
+
+  // Disabling verification makes the connection vulnerable to man-in-the-middle attacks
+  pub fn verify_hostname(mut self, verify_hostname: bool) -> Tls {
+    self.verify_hostname = verify_hostname;
+    self
+  }
+
+  pub fn verify_certificate(mut self, verify_certificate: bool) -> Tls {
+    self.verify_certificate = verify_certificate;
+    self
+  }
+
+  // Files are read each time so that rotated certificates are picked up on reconnect
+  pub fn connector(&self) -> Result<TlsConnector> {
+    let mut builder = TlsConnector::builder();
+    for path in self.ca_bundles.iter() {
+      let pem = try!(Tls::read_file(path));
+      // A bundle may hold several certificates
+      let certificates = try!(Certificate::stack_from_pem(&pem).map_err(|error| tls_error("Could not load CA bundle", error)));
+      for certificate in certificates {
+        builder.add_root_certificate(certificate);
+      }
+    }
+    if let Some((ref certificate_path, ref private_key_path)) = self.client_certificate {
+      let certificate = try!(Tls::read_file(certificate_path));
+      let private_key = try!(Tls::read_file(private_key_path));
+      let identity = try!(Identity::from_pkcs8(&certificate, &private_key).map_err(|error| tls_error("Could not load client certificate", error)));
+      builder.identity(identity);
+    }
+    builder.use_sni(self.use_sni);
+    builder.danger_accept_invalid_hostnames(!self.verify_hostname);
+    builder.danger_accept_invalid_certs(!self.verify_certificate);
+    builder.build().map_err(|error| tls_error("Could not configure TLS", error))
+  }
+
+  pub fn wrap(&self, host: &str, tcp_stream: TcpStream) -> Result<TlsStream<TcpStream>> {
+    let connector = try!(self.connector());
+    let server_name = match self.server_name {
+      Some(ref server_name) => server_name.as_ref(),
+      None => connection::unbracketed(host)
+    };
+    debug!("Starting TLS handshake with '{}'.", server_name);
+    match connector.connect(server_name, tcp_stream) {
+      Ok(tls_stream) => Ok(tls_stream),
+      Err(HandshakeError::Failure(error)) => Err(tls_error("TLS handshake failed", error)),
+      // The socket is still blocking at this point, so only a handshake timeout can interrupt it
+      Err(HandshakeError::WouldBlock(_)) => Err(Error::new(WouldBlock, "TLS handshake timed out."))
+    }
+  }
+
+  pub fn connect(&self, host: &str, port: u16, timeouts: Timeouts, proxy: Option<&Proxy>) -> Result<TlsTransport> {
+    let tcp_stream = try!(connection::open_tcp(host, port, timeouts, proxy));
+    let tls_stream = try!(self.wrap(host, tcp_stream));
+    Ok(TlsTransport {
+      stream: tls_stream,
+      host: host.to_string(),
+      port: port,
+      tls: self.clone(),
+      timeouts: timeouts,
+      proxy: proxy.cloned()
+    })
+  }
+
+  fn read_file(path: &str) -> Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    let mut file = try!(File::open(path));
+    try!(file.read_to_end(&mut contents));
+    Ok(contents)
+  }
+}
+
+// A TLS stream along with what is needed to reopen it
+pub struct TlsTransport {
+  stream: TlsStream<TcpStream>,
+  host: String,
+  port: u16,
+  tls: Tls,
+  timeouts: Timeouts,
+  proxy: Option<Proxy>
+}
+
+impl TlsTransport {
+  pub fn get_ref(&self) -> &TlsStream<TcpStream> {
+    &self.stream
+  }
+}
+
+impl Read for TlsTransport {
+  fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+    self.stream.read(buffer)
+  }
+}
+
+impl Write for TlsTransport {
+  fn write(&mut self, bytes: &[u8]) -> Result<usize> {
+    self.stream.write(bytes)
+  }
+
+  fn flush(&mut self) -> Result<()> {
+    self.stream.flush()
+  }
+}
+
+impl AsRawFd for TlsTransport {
+  fn as_raw_fd(&self) -> RawFd {
+    self.stream.get_ref().as_raw_fd()
+  }
+}
+
+impl Transport for TlsTransport {
+  fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+    self.stream.get_ref().set_nonblocking(nonblocking)
+  }
+
+  fn shutdown(&self, how: Shutdown) -> Result<()> {
+    self.stream.get_ref().shutdown(how)
+  }
+
+  fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+    self.stream.get_ref().set_read_timeout(timeout)
+  }
+
+  fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+    self.stream.get_ref().set_write_timeout(timeout)
+  }
+
+  fn buffers_reads(&self) -> bool {
+    true
+  }
+
+  fn reopen(&self) -> Result<Box<Transport>> {
+    let tls_transport = try!(self.tls.connect(&self.host, self.port, self.timeouts, self.proxy.as_ref()));
+    Ok(Box::new(tls_transport))
+  }
+}
+
+fn tls_error(context: &str, error: ::native_tls::Error) -> Error {
+  Error::new(InvalidData, format!("{}: {}", context, error))
+}
+
+#[test]
+fn missing_ca_bundle_is_reported() {
+  let tls = Tls::new().ca_bundle("/nonexistent/stomp-rs/ca.pem");
+  match tls.connector() {
+    Err(error) => assert_eq!(::std::io::ErrorKind::NotFound, error.kind()),
+    Ok(_) => panic!("Expected the missing CA bundle to be reported.")
+  }
+}
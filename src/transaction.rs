@@ -19,11 +19,13 @@ impl <'a, 'session> Transaction<'a, 'session> {
   }
 
   pub fn message<'b, T: ToFrameBody> (&'b mut self, destination: & str, body_convertible: T) -> MessageBuilder<'b, 'session> {
-    let mut send_frame = Frame::send(destination, body_convertible.to_frame_body());
+    let mut send_frame = Frame::send_owned(destination, body_convertible.into_frame_body());
     send_frame.headers.push(Header::new("transaction", self.id.as_ref()));
     MessageBuilder {
      session: self.session,
-     frame: send_frame
+     frame: send_frame,
+     body_source: None,
+     delay: None
     }
   }
 
@@ -0,0 +1,225 @@
+use std::net::{TcpStream, Shutdown, SocketAddr};
+use std::io::{Read, Write};
+use std::io::Result;
+use std::time::Duration;
+use std::io::Error;
+use std::io::ErrorKind::InvalidInput;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use mio::Evented;
+
+// The byte stream a session exchanges frames over. The event loop polls its file descriptor.
+pub trait Transport: Read + Write + AsRawFd {
+  fn set_nonblocking(&self, nonblocking: bool) -> Result<()>;
+
+  fn shutdown(&self, how: Shutdown) -> Result<()>;
+
+  // Only apply while the transport is blocking; None waits indefinitely
+  fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()>;
+
+  fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()>;
+
+  // TLS decrypts whole records at a time, so a read can leave bytes behind that the
+  // socket will never signal as readable again. Such transports must be read until they block.
+  fn buffers_reads(&self) -> bool {
+    false
+  }
+
+  // Bytes that were accepted by write() but are still held by the transport itself
+  fn has_pending_writes(&self) -> bool {
+    false
+  }
+
+  // Opens a new transport to the same peer, used when the session reconnects
+  fn reopen(&self) -> Result<Box<Transport>>;
+}
+
+// A TCP connection along with the address of its peer, which the socket stops reporting
+// once the connection has been lost
+pub struct TcpTransport {
+  stream: TcpStream,
+  peer_address: SocketAddr
+}
+
+impl TcpTransport {
+  pub fn new(stream: TcpStream) -> Result<TcpTransport> {
+    let peer_address = try!(stream.peer_addr());
+    Ok(TcpTransport {
+      stream: stream,
+      peer_address: peer_address
+    })
+  }
+
+  pub fn get_ref(&self) -> &TcpStream {
+    &self.stream
+  }
+
+  pub fn peer_address(&self) -> SocketAddr {
+    self.peer_address
+  }
+}
+
+impl Read for TcpTransport {
+  fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+    self.stream.read(buffer)
+  }
+}
+
+impl Write for TcpTransport {
+  fn write(&mut self, bytes: &[u8]) -> Result<usize> {
+    self.stream.write(bytes)
+  }
+
+  fn flush(&mut self) -> Result<()> {
+    self.stream.flush()
+  }
+}
+
+impl AsRawFd for TcpTransport {
+  fn as_raw_fd(&self) -> RawFd {
+    self.stream.as_raw_fd()
+  }
+}
+
+impl Transport for TcpTransport {
+  fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+    self.stream.set_nonblocking(nonblocking)
+  }
+
+  fn shutdown(&self, how: Shutdown) -> Result<()> {
+    self.stream.shutdown(how)
+  }
+
+  fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+    self.stream.set_read_timeout(timeout)
+  }
+
+  fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+    self.stream.set_write_timeout(timeout)
+  }
+
+  fn reopen(&self) -> Result<Box<Transport>> {
+    let tcp_stream = try!(TcpStream::connect(self.peer_address));
+    Ok(Box::new(try!(TcpTransport::new(tcp_stream))))
+  }
+}
+
+impl Transport for UnixStream {
+  fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+    UnixStream::set_nonblocking(self, nonblocking)
+  }
+
+  fn shutdown(&self, how: Shutdown) -> Result<()> {
+    UnixStream::shutdown(self, how)
+  }
+
+  fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+    UnixStream::set_read_timeout(self, timeout)
+  }
+
+  fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+    UnixStream::set_write_timeout(self, timeout)
+  }
+
+  fn reopen(&self) -> Result<Box<Transport>> {
+    let peer_address = try!(self.peer_addr());
+    match peer_address.as_pathname() {
+      Some(path) => Ok(Box::new(try!(UnixStream::connect(path)))),
+      // e.g. one end of a UnixStream::pair()
+      None => Err(Error::new(InvalidInput, "Cannot reopen an unnamed Unix domain socket."))
+    }
+  }
+}
+
+impl Transport for Box<Transport> {
+  fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+    (**self).set_nonblocking(nonblocking)
+  }
+
+  fn shutdown(&self, how: Shutdown) -> Result<()> {
+    (**self).shutdown(how)
+  }
+
+  fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+    (**self).set_read_timeout(timeout)
+  }
+
+  fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+    (**self).set_write_timeout(timeout)
+  }
+
+  fn buffers_reads(&self) -> bool {
+    (**self).buffers_reads()
+  }
+
+  fn has_pending_writes(&self) -> bool {
+    (**self).has_pending_writes()
+  }
+
+  fn reopen(&self) -> Result<Box<Transport>> {
+    (**self).reopen()
+  }
+}
+
+impl AsRawFd for Box<Transport> {
+  fn as_raw_fd(&self) -> RawFd {
+    (**self).as_raw_fd()
+  }
+}
+
+// The event loop only needs the underlying socket's file descriptor
+impl Evented for Box<Transport> {}
+
+#[test]
+fn tcp_transports_reopen_to_the_same_peer() {
+  use std::net::TcpListener;
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let mut first_transport = TcpTransport::new(TcpStream::connect(listener.local_addr().unwrap()).unwrap()).unwrap();
+  let (mut first_peer, _) = listener.accept().unwrap();
+  first_transport.write_all(b"Animal").unwrap();
+  let mut buffer = [0u8; 7];
+  first_peer.read_exact(&mut buffer[..6]).unwrap();
+  assert_eq!(b"Animal", &buffer[..6]);
+
+  // A dead socket no longer knows its peer, so the address has to have been kept
+  drop(first_peer);
+  first_transport.shutdown(Shutdown::Both).unwrap();
+  assert!(first_transport.get_ref().peer_addr().is_err());
+  let mut second_transport = first_transport.reopen().unwrap();
+  let (mut second_peer, _) = listener.accept().unwrap();
+  second_transport.write_all(b"Mineral").unwrap();
+  second_peer.read_exact(&mut buffer).unwrap();
+  assert_eq!(b"Mineral", &buffer);
+}
+
+#[test]
+fn sessions_start_over_a_supplied_transport() {
+  use std::thread;
+  use session_builder::SessionBuilder;
+  use connection::{HeartBeat, Credentials};
+  use header::StompVersion;
+  let (client_end, mut broker_end) = UnixStream::pair().unwrap();
+  let broker = thread::spawn(move || {
+    let mut connect_frame = Vec::new();
+    let mut byte = [0u8; 1];
+    while broker_end.read(&mut byte).unwrap() == 1 && byte[0] != 0 {
+      connect_frame.push(byte[0]);
+    }
+    broker_end.write_all(b"CONNECTED\nversion:1.1\nserver:FakeMQ/1.0\nsession:ID\\c42\nheart-beat:0,5000\n\n\0").unwrap();
+    String::from_utf8(connect_frame).unwrap()
+  });
+  let session = SessionBuilder::new("localhost", 61613)
+    .with(HeartBeat(1000, 1000))
+    .with(Credentials("guest", "pass:word"))
+    .start_with_transport(Box::new(client_end))
+    .unwrap();
+  assert!(session.connection.reopen_transport);
+  assert_eq!(StompVersion::Stomp_v1_1, session.version());
+  assert_eq!(Some("FakeMQ/1.0"), session.server());
+  // CONNECTED headers are not escaped, so the backslash is part of the id
+  assert_eq!(Some("ID\\c42"), session.session_id());
+  assert_eq!((5000, 0), session.heartbeat());
+  let connect_frame = broker.join().unwrap();
+  assert!(connect_frame.starts_with("CONNECT\n"));
+  assert!(connect_frame.contains("\npasscode:pass:word\n"));
+}
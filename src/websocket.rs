@@ -0,0 +1,489 @@
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::io::Read;
+use std::io::Write;
+use std::io::Result;
+use std::io::Error;
+use std::io::ErrorKind::{InvalidData, UnexpectedEof, WouldBlock, WriteZero};
+use std::process;
+use std::str::from_utf8;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use base64;
+use sha1_smol::Sha1;
+use transport::Transport;
+
+const HANDSHAKE_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const SUBPROTOCOLS: &'static str = "v12.stomp, v11.stomp, v10.stomp";
+const MAX_HANDSHAKE_RESPONSE_SIZE: usize = 16 * 1024;
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+// Upper bound on a message, including any continuation frames, so a bogus length can't
+// make us buffer without end
+const MAX_MESSAGE_SIZE: u64 = 64 * 1024 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MessageType {
+  Text,
+  Binary
+}
+
+// Carry STOMP frames inside WebSocket messages, e.g. for RabbitMQ Web-STOMP
+#[derive(Clone)]
+pub struct WebSocket {
+  pub path: String,
+  pub message_type: MessageType
+}
+
+impl WebSocket {
+  pub fn new(path: &str) -> WebSocket {
+    WebSocket {
+      path: path.to_string(),
+      message_type: MessageType::Binary
+    }
+  }
+
+  pub fn binary(mut self) -> WebSocket {
+    self.message_type = MessageType::Binary;
+    self
+  }
+
+  // For servers that only accept text messages. Text messages must be valid UTF-8, so every
+  // message body has to be as well.
+  pub fn text(mut self) -> WebSocket {
+    self.message_type = MessageType::Text;
+    self
+  }
+}
+
+// Client side of a WebSocket connection, presented as a plain byte stream. Message boundaries
+// are irrelevant to STOMP, so payloads are simply concatenated and handed to the frame parser.
+pub struct WebSocketStream<S: Read + Write> {
+  inner: S,
+  // Kept so that the connection can be reopened
+  host: String,
+  port: u16,
+  settings: WebSocket,
+  message_type: MessageType,
+  // Bytes read from `inner` that do not form a complete WebSocket frame yet
+  raw: Vec<u8>,
+  // Payload bytes that have not been read yet
+  payload: Vec<u8>,
+  payload_position: usize,
+  // Encoded frames that have not been written to `inner` yet
+  outgoing: Vec<u8>,
+  outgoing_position: usize,
+  closed: bool,
+  mask_state: u32
+}
+
+// IPv6 addresses must be bracketed in the Host header
+fn host_header(host: &str) -> String {
+  if host.contains(':') && !host.starts_with('[') {
+    format!("[{}]", host)
+  } else {
+    host.to_string()
+  }
+}
+
+impl <S: Read + Write> WebSocketStream<S> {
+  // Performs the HTTP upgrade on a blocking stream
+  pub fn connect(mut inner: S, host: &str, port: u16, settings: &WebSocket) -> Result<WebSocketStream<S>> {
+    let mut mask_state = WebSocketStream::<S>::seed();
+    let mut key_bytes = Vec::with_capacity(16);
+    for _ in 0..4 {
+      let random = WebSocketStream::<S>::next_random(&mut mask_state);
+      key_bytes.extend_from_slice(&[(random >> 24) as u8, (random >> 16) as u8, (random >> 8) as u8, random as u8]);
+    }
+    let key = base64::encode(&key_bytes);
+    let request = format!(
+      "GET {} HTTP/1.1\r\nHost: {}:{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: {}\r\n\r\n",
+      settings.path, host_header(host), port, key, SUBPROTOCOLS
+    );
+    try!(inner.write_all(request.as_bytes()));
+    try!(inner.flush());
+
+    let mut response = Vec::new();
+    let mut chunk = [0u8; 1024];
+    let headers_end = loop {
+      if let Some(index) = response.windows(4).position(|window| window == b"\r\n\r\n") {
+        break index + 4;
+      }
+      if response.len() > MAX_HANDSHAKE_RESPONSE_SIZE {
+        return Err(Error::new(InvalidData, "WebSocket handshake response was too large."));
+      }
+      let bytes_read = try!(inner.read(&mut chunk));
+      if bytes_read == 0 {
+        return Err(Error::new(UnexpectedEof, "Connection closed during the WebSocket handshake."));
+      }
+      response.extend_from_slice(&chunk[..bytes_read]);
+    };
+    {
+      let response_headers = try!(from_utf8(&response[..headers_end])
+        .map_err(|_| Error::new(InvalidData, "WebSocket handshake response was not utf8.")));
+      try!(WebSocketStream::<S>::check_handshake_response(response_headers, &key));
+    }
+    // The server may have sent its first frames along with the response
+    let leftover = response[headers_end..].to_vec();
+    Ok(WebSocketStream {
+      inner: inner,
+      host: host.to_string(),
+      port: port,
+      settings: settings.clone(),
+      message_type: settings.message_type,
+      raw: leftover,
+      payload: Vec::new(),
+      payload_position: 0,
+      outgoing: Vec::new(),
+      outgoing_position: 0,
+      closed: false,
+      mask_state: mask_state
+    })
+  }
+
+  pub fn get_ref(&self) -> &S {
+    &self.inner
+  }
+
+  fn check_handshake_response(response: &str, key: &str) -> Result<()> {
+    let mut lines = response.split("\r\n");
+    let status_line = lines.next().unwrap_or("");
+    if !status_line.starts_with("HTTP/1.1 101") {
+      return Err(Error::new(InvalidData, format!("WebSocket upgrade was refused: '{}'", status_line)));
+    }
+    let expected_accept = base64::encode(&Sha1::from(format!("{}{}", key, HANDSHAKE_GUID)).digest().bytes());
+    let mut accepted = false;
+    for line in lines {
+      let mut parts = line.splitn(2, ':');
+      let name = parts.next().unwrap_or("").trim().to_lowercase();
+      let value = parts.next().unwrap_or("").trim();
+      match name.as_ref() {
+        "sec-websocket-accept" => accepted = value == expected_accept,
+        "sec-websocket-protocol" => debug!("Server selected WebSocket subprotocol '{}'.", value),
+        _ => {}
+      }
+    }
+    if !accepted {
+      return Err(Error::new(InvalidData, "Server sent an invalid Sec-WebSocket-Accept header."));
+    }
+    Ok(())
+  }
+
+  fn seed() -> u32 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    (now.subsec_nanos() ^ (now.as_secs() as u32) ^ (process::id() << 16)) | 1
+  }
+
+  // Masking keys only need to be unpredictable to intermediaries, not cryptographically strong
+  fn next_random(state: &mut u32) -> u32 {
+    let mut x = *state;
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    *state = x;
+    x
+  }
+
+  fn queue_frame(&mut self, opcode: u8, payload: &[u8]) {
+    self.outgoing.push(0x80 | opcode);
+    let length = payload.len();
+    if length < 126 {
+      self.outgoing.push(0x80 | length as u8);
+    } else if length <= 0xFFFF {
+      self.outgoing.push(0x80 | 126);
+      self.outgoing.extend_from_slice(&[(length >> 8) as u8, length as u8]);
+    } else {
+      self.outgoing.push(0x80 | 127);
+      for shift in (0..8).rev() {
+        self.outgoing.push(((length as u64) >> (shift * 8)) as u8);
+      }
+    }
+    // Frames sent by a client must always be masked
+    let random = WebSocketStream::<S>::next_random(&mut self.mask_state);
+    let mask = [(random >> 24) as u8, (random >> 16) as u8, (random >> 8) as u8, random as u8];
+    self.outgoing.extend_from_slice(&mask);
+    self.outgoing.extend(payload.iter().enumerate().map(|(index, byte)| byte ^ mask[index % 4]));
+  }
+
+  fn write_pending(&mut self) -> Result<()> {
+    while self.outgoing_position < self.outgoing.len() {
+      match try!(self.inner.write(&self.outgoing[self.outgoing_position..])) {
+        0 => return Err(Error::new(WriteZero, "The stream did not accept any bytes.")),
+        bytes_written => self.outgoing_position += bytes_written
+      }
+    }
+    self.outgoing.clear();
+    self.outgoing_position = 0;
+    self.inner.flush()
+  }
+
+  // Queues a reply that can be written later if the stream would block right now
+  fn reply(&mut self, opcode: u8, payload: &[u8]) -> Result<()> {
+    self.queue_frame(opcode, payload);
+    match self.write_pending() {
+      Err(ref error) if error.kind() == WouldBlock => Ok(()),
+      result => result
+    }
+  }
+
+  // Moves the payload of the next complete frame in `raw` into `payload`.
+  // Returns false if `raw` doesn't hold a complete frame.
+  fn decode_frame(&mut self) -> Result<bool> {
+    if self.raw.len() < 2 {
+      return Ok(false);
+    }
+    let opcode = self.raw[0] & 0x0F;
+    let masked = self.raw[1] & 0x80 != 0;
+    let (length, mut header_length) = match self.raw[1] & 0x7F {
+      126 if self.raw.len() >= 4 => (((self.raw[2] as u64) << 8) | self.raw[3] as u64, 4),
+      127 if self.raw.len() >= 10 => (self.raw[2..10].iter().fold(0u64, |length, byte| (length << 8) | *byte as u64), 10),
+      126 | 127 => return Ok(false),
+      length => (length as u64, 2)
+    };
+    let buffered_length = (self.payload.len() - self.payload_position) as u64;
+    if length > MAX_MESSAGE_SIZE || buffered_length + length > MAX_MESSAGE_SIZE {
+      return Err(Error::new(InvalidData, format!("WebSocket message exceeds the maximum size of {} bytes.", MAX_MESSAGE_SIZE)));
+    }
+    let length = length as usize;
+    let mut mask = None;
+    if masked {
+      if self.raw.len() < header_length + 4 {
+        return Ok(false);
+      }
+      mask = Some([self.raw[header_length], self.raw[header_length + 1], self.raw[header_length + 2], self.raw[header_length + 3]]);
+      header_length += 4;
+    }
+    let frame_length = match header_length.checked_add(length) {
+      Some(frame_length) => frame_length,
+      None => return Err(Error::new(InvalidData, "WebSocket frame length overflowed."))
+    };
+    if self.raw.len() < frame_length {
+      return Ok(false);
+    }
+    let mut frame_payload: Vec<u8> = self.raw.drain(..frame_length).skip(header_length).collect();
+    if let Some(mask) = mask {
+      for (index, byte) in frame_payload.iter_mut().enumerate() {
+        *byte ^= mask[index % 4];
+      }
+    }
+    match opcode {
+      OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+        if self.payload_position == self.payload.len() {
+          self.payload.clear();
+          self.payload_position = 0;
+        }
+        self.payload.extend_from_slice(&frame_payload);
+      },
+      OPCODE_PING => try!(self.reply(OPCODE_PONG, &frame_payload)),
+      OPCODE_PONG => {},
+      OPCODE_CLOSE => {
+        debug!("Server closed the WebSocket connection.");
+        if !self.closed {
+          self.closed = true;
+          let status: Vec<u8> = frame_payload.iter().take(2).cloned().collect();
+          try!(self.reply(OPCODE_CLOSE, &status));
+        }
+      },
+      _ => return Err(Error::new(InvalidData, format!("Unknown WebSocket opcode {}.", opcode)))
+    }
+    Ok(true)
+  }
+}
+
+impl <S: Read + Write> Read for WebSocketStream<S> {
+  fn read(&mut self, destination: &mut [u8]) -> Result<usize> {
+    loop {
+      if self.payload_position < self.payload.len() {
+        let count = ::std::cmp::min(destination.len(), self.payload.len() - self.payload_position);
+        destination[..count].copy_from_slice(&self.payload[self.payload_position..self.payload_position + count]);
+        self.payload_position += count;
+        return Ok(count);
+      }
+      if self.closed {
+        return Ok(0);
+      }
+      if try!(self.decode_frame()) {
+        continue;
+      }
+      let mut chunk = [0u8; READ_CHUNK_SIZE];
+      let bytes_read = try!(self.inner.read(&mut chunk));
+      if bytes_read == 0 {
+        return Ok(0);
+      }
+      self.raw.extend_from_slice(&chunk[..bytes_read]);
+    }
+  }
+}
+
+impl <S: Read + Write> Write for WebSocketStream<S> {
+  // Each call becomes one WebSocket message. New data is only accepted once earlier messages
+  // have been written completely, so WouldBlock reaches the caller as usual.
+  fn write(&mut self, bytes: &[u8]) -> Result<usize> {
+    try!(self.write_pending());
+    let opcode = match self.message_type {
+      MessageType::Text => OPCODE_TEXT,
+      MessageType::Binary => OPCODE_BINARY
+    };
+    self.queue_frame(opcode, bytes);
+    match self.write_pending() {
+      Err(ref error) if error.kind() == WouldBlock => {},
+      Err(error) => return Err(error),
+      Ok(_) => {}
+    }
+    Ok(bytes.len())
+  }
+
+  fn flush(&mut self) -> Result<()> {
+    self.write_pending()
+  }
+}
+
+impl <S: Transport> AsRawFd for WebSocketStream<S> {
+  fn as_raw_fd(&self) -> RawFd {
+    self.inner.as_raw_fd()
+  }
+}
+
+impl <S: Transport> Transport for WebSocketStream<S> {
+  fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+    self.inner.set_nonblocking(nonblocking)
+  }
+
+  fn shutdown(&self, how: Shutdown) -> Result<()> {
+    self.inner.shutdown(how)
+  }
+
+  fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+    self.inner.set_read_timeout(timeout)
+  }
+
+  fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+    self.inner.set_write_timeout(timeout)
+  }
+
+  // Payloads of frames that have already been read from the inner transport may be waiting
+  fn buffers_reads(&self) -> bool {
+    true
+  }
+
+  fn has_pending_writes(&self) -> bool {
+    self.outgoing_position < self.outgoing.len()
+  }
+
+  fn reopen(&self) -> Result<Box<Transport>> {
+    let inner = try!(self.inner.reopen());
+    let websocket_stream = try!(WebSocketStream::connect(inner, &self.host, self.port, &self.settings));
+    Ok(Box::new(websocket_stream))
+  }
+}
+
+// Answers the upgrade request it receives, then replays `frames`
+#[cfg(test)]
+struct FakeServer {
+  frames: Vec<u8>,
+  responded: bool,
+  incoming: ::std::io::Cursor<Vec<u8>>,
+  written: Vec<u8>,
+  accept_override: Option<&'static str>
+}
+
+#[cfg(test)]
+impl FakeServer {
+  fn new(frames: Vec<u8>) -> FakeServer {
+    FakeServer {
+      frames: frames,
+      responded: false,
+      incoming: ::std::io::Cursor::new(Vec::new()),
+      written: Vec::new(),
+      accept_override: None
+    }
+  }
+}
+
+#[cfg(test)]
+impl Read for FakeServer {
+  fn read(&mut self, destination: &mut [u8]) -> Result<usize> {
+    if !self.responded {
+      self.responded = true;
+      let accept = match self.accept_override {
+        Some(accept) => accept.to_string(),
+        None => {
+          let request = from_utf8(&self.written).unwrap();
+          let key_line = request.split("\r\n").find(|line| line.starts_with("Sec-WebSocket-Key: ")).unwrap();
+          let key = &key_line["Sec-WebSocket-Key: ".len()..];
+          base64::encode(&Sha1::from(format!("{}{}", key, HANDSHAKE_GUID)).digest().bytes())
+        }
+      };
+      let mut response = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\nSec-WebSocket-Protocol: v12.stomp\r\n\r\n", accept).into_bytes();
+      response.extend_from_slice(&self.frames);
+      self.incoming = ::std::io::Cursor::new(response);
+      self.written.clear();
+    }
+    self.incoming.read(destination)
+  }
+}
+
+#[cfg(test)]
+impl Write for FakeServer {
+  fn write(&mut self, bytes: &[u8]) -> Result<usize> {
+    self.written.extend_from_slice(bytes);
+    Ok(bytes.len())
+  }
+
+  fn flush(&mut self) -> Result<()> {
+    Ok(())
+  }
+}
+
+#[test]
+fn handshake_validates_the_accept_header() {
+  let mut server = FakeServer::new(Vec::new());
+  server.accept_override = Some("bogus");
+  assert!(WebSocketStream::connect(server, "localhost", 15674, &WebSocket::new("/ws")).is_err());
+}
+
+#[test]
+fn payloads_are_unwrapped_and_writes_are_masked() {
+  // "CONNECTED\n\n\0" split across two server messages, with a ping in between
+  let mut frames = vec![0x01, 0x05];
+  frames.extend_from_slice(b"CONNE");
+  frames.extend_from_slice(&[0x89, 0x00]);
+  frames.extend_from_slice(&[0x80, 0x07]);
+  frames.extend_from_slice(b"CTED\n\n\0");
+  let mut stream = WebSocketStream::connect(FakeServer::new(frames), "localhost", 15674, &WebSocket::new("/ws")).unwrap();
+
+  let mut received = Vec::new();
+  stream.read_to_end(&mut received).unwrap();
+  assert_eq!(b"CONNECTED\n\n\0", &received[..]);
+  // The ping was answered with a masked, empty pong
+  assert_eq!(&[0x8A, 0x80], &stream.get_ref().written[..2]);
+
+  stream.inner.written.clear();
+  stream.write_all(b"SEND").unwrap();
+  let written = &stream.get_ref().written;
+  // Binary unless text messages were asked for
+  assert_eq!(&[0x82, 0x84], &written[..2]);
+  let mask = &written[2..6];
+  let unmasked: Vec<u8> = written[6..].iter().enumerate().map(|(index, byte)| byte ^ mask[index % 4]).collect();
+  assert_eq!(b"SEND", &unmasked[..]);
+
+  let mut text_stream = WebSocketStream::connect(FakeServer::new(Vec::new()), "localhost", 15674, &WebSocket::new("/ws").text()).unwrap();
+  text_stream.write_all(b"SEND").unwrap();
+  // The upgrade request comes first, followed by the 10 byte message
+  let written = &text_stream.get_ref().written;
+  assert_eq!(&[0x81, 0x84], &written[written.len() - 10..written.len() - 8]);
+}
+
+#[test]
+fn oversized_lengths_are_rejected() {
+  let mut frames = vec![0x82, 0x7F];
+  frames.extend_from_slice(&[0xFF; 8]);
+  let mut stream = WebSocketStream::connect(FakeServer::new(frames), "localhost", 15674, &WebSocket::new("/ws")).unwrap();
+  let mut received = Vec::new();
+  assert_eq!(InvalidData, stream.read_to_end(&mut received).unwrap_err().kind());
+}